@@ -1,10 +1,12 @@
 use ring::rand;
 use ring::signature::{self, EcdsaKeyPair, RsaKeyPair};
 
+// Both key pairs are boxed: an unboxed EcdsaKeyPair is over 240 bytes and
+// would make every Signer that large (clippy::large_enum_variant)
 #[derive(Debug)]
 pub enum Signer {
     Rsa(Box<RsaKeyPair>),
-    Ecdsa(EcdsaKeyPair),
+    Ecdsa(Box<EcdsaKeyPair>),
 }
 
 impl Signer {
@@ -26,7 +28,7 @@ impl Signer {
                         SignerError::KeyError(format!("error decoding EC private key: {}", e))
                     })?;
 
-            return Ok(Signer::Ecdsa(keypair));
+            return Ok(Signer::Ecdsa(Box::new(keypair)));
         }
 
         Err(SignerError::KeyError("unsupported key type".to_string()))
@@ -2,10 +2,11 @@ use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::{Duration, Instant, SystemTime};
 
-use intersight_api::Client;
+use anyhow::{Context, Result};
 use opentelemetry_proto::tonic::common::v1::{any_value, AnyValue, KeyValue};
 use tokio::sync::Mutex;
 
+use crate::coalescing_client::CoalescingClient;
 use crate::config::AttributeEnricherConfig;
 use crate::intersight_poller::IntersightMetricBatch;
 
@@ -27,26 +28,27 @@ fn jitter_secs() -> u64 {
 pub struct AttributeEnricher {
     config: AttributeEnricherConfig,
     cache: Mutex<HashMap<String, CacheEntry>>,
-    client: Client,
+    client: CoalescingClient,
     compiled_regex: Option<regex::Regex>,
 }
 
 impl AttributeEnricher {
-    pub fn new(config: AttributeEnricherConfig, client: Client) -> Self {
-        let compiled_regex = config.source_value_regex.as_ref().map(|pattern| {
-            regex::Regex::new(pattern).unwrap_or_else(|e| {
-                panic!(
-                    "Enricher '{}': invalid source_value_regex '{}': {}",
-                    config.name, pattern, e
+    pub fn new(config: AttributeEnricherConfig, client: CoalescingClient) -> Result<Self> {
+        let compiled_regex = match &config.source_value_regex {
+            Some(pattern) => Some(regex::Regex::new(pattern).with_context(|| {
+                format!(
+                    "Enricher '{}': invalid source_value_regex '{}'",
+                    config.name, pattern
                 )
-            })
-        });
-        AttributeEnricher {
+            })?),
+            None => None,
+        };
+        Ok(AttributeEnricher {
             config,
             cache: Mutex::new(HashMap::new()),
             client,
             compiled_regex,
-        }
+        })
     }
 
     pub async fn enrich_batch(&self, batch: &mut IntersightMetricBatch) {
@@ -125,8 +127,7 @@ impl AttributeEnricher {
 
         let result = self.do_lookup(source_value).await;
 
-        let expires_at =
-            Instant::now() + Duration::from_secs(CACHE_TTL_SECS + jitter_secs());
+        let expires_at = Instant::now() + Duration::from_secs(CACHE_TTL_SECS + jitter_secs());
         let mut cache = self.cache.lock().await;
         cache.insert(
             source_value.to_string(),
@@ -190,15 +191,13 @@ fn extract_field(response: &serde_json::Value, field: &str) -> Option<String> {
 
 pub fn build_enricher_map(
     configs: &[AttributeEnricherConfig],
-    client: &Client,
-) -> HashMap<String, Arc<AttributeEnricher>> {
+    client: &CoalescingClient,
+) -> Result<HashMap<String, Arc<AttributeEnricher>>> {
     configs
         .iter()
         .map(|c| {
-            (
-                c.name.clone(),
-                Arc::new(AttributeEnricher::new(c.clone(), client.clone())),
-            )
+            let enricher = AttributeEnricher::new(c.clone(), client.clone())?;
+            Ok((c.name.clone(), Arc::new(enricher)))
         })
         .collect()
 }
@@ -257,28 +256,32 @@ pQ8EfDaxnEFVuY7Xa8i/qr7mmXo5E+d0TrxkB1bqtwaJJ8ojaW5G/PIkU3aTC6uV
 11QYh2F1qu2ow8Y4Q3DZ78jc9M3gHvzuknyencU2K0+VhVgwEVtI
 -----END RSA PRIVATE KEY-----";
 
-    fn test_client() -> Client {
-        intersight_api::config::Config::new()
+    fn test_client() -> CoalescingClient {
+        let client = intersight_api::config::Config::new()
             .with_key_id(TEST_KEY_ID)
             .with_key_bytes(TEST_PEM)
             .build_client()
-            .expect("failed to build test client")
+            .expect("failed to build test client");
+        CoalescingClient::new(client, true, None)
     }
 
     fn make_enricher(name: &str) -> Arc<AttributeEnricher> {
-        Arc::new(AttributeEnricher::new(
-            AttributeEnricherConfig {
-                name: name.to_string(),
-                source_attribute: "some.attribute".to_string(),
-                source_value_regex: None,
-                query_template: "api/v1/thing/{value}".to_string(),
-                result_mappings: vec![ResultMappingConfig {
-                    result_field: "Name".to_string(),
-                    result_attribute: "thing.name".to_string(),
-                }],
-            },
-            test_client(),
-        ))
+        Arc::new(
+            AttributeEnricher::new(
+                AttributeEnricherConfig {
+                    name: name.to_string(),
+                    source_attribute: "some.attribute".to_string(),
+                    source_value_regex: None,
+                    query_template: "api/v1/thing/{value}".to_string(),
+                    result_mappings: vec![ResultMappingConfig {
+                        result_field: "Name".to_string(),
+                        result_attribute: "thing.name".to_string(),
+                    }],
+                },
+                test_client(),
+            )
+            .unwrap(),
+        )
     }
 
     // --- extract_field tests ---
@@ -390,7 +393,7 @@ pQ8EfDaxnEFVuY7Xa8i/qr7mmXo5E+d0TrxkB1bqtwaJJ8ojaW5G/PIkU3aTC6uV
             },
         ];
         let client = test_client();
-        let map = build_enricher_map(&configs, &client);
+        let map = build_enricher_map(&configs, &client).unwrap();
         assert_eq!(map.len(), 2);
         assert!(map.contains_key("enricher_a"));
         assert!(map.contains_key("enricher_b"));
@@ -399,10 +402,25 @@ pQ8EfDaxnEFVuY7Xa8i/qr7mmXo5E+d0TrxkB1bqtwaJJ8ojaW5G/PIkU3aTC6uV
     #[test]
     fn test_build_enricher_map_empty() {
         let client = test_client();
-        let map = build_enricher_map(&[], &client);
+        let map = build_enricher_map(&[], &client).unwrap();
         assert!(map.is_empty());
     }
 
+    #[test]
+    fn test_build_enricher_map_invalid_regex() {
+        let configs = vec![AttributeEnricherConfig {
+            name: "profile".to_string(),
+            source_attribute: "host.id".to_string(),
+            source_value_regex: Some("([".to_string()),
+            query_template: "api/v1/a/{value}".to_string(),
+            result_mappings: vec![],
+        }];
+        let err = build_enricher_map(&configs, &test_client()).err().unwrap();
+        assert!(err
+            .to_string()
+            .starts_with("Enricher 'profile': invalid source_value_regex '(['"));
+    }
+
     // --- apply_regex tests ---
 
     #[test]
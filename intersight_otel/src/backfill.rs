@@ -0,0 +1,347 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use tokio::sync::mpsc::Sender;
+use tokio::task::JoinHandle;
+use tokio::time::{self, MissedTickBehavior};
+
+use crate::attribute_enricher::{self, AttributeEnricher};
+use crate::coalescing_client::CoalescingClient;
+use crate::config::{BackfillArgs, GlobalConfig, TSPollerConfig};
+use crate::intersight_poller::{self, IntersightMetricBatch, QueryWindow};
+use crate::metric_merger::{self, ExportRequest, MetricRouter};
+
+const MAX_ATTEMPTS: u32 = 3;
+
+/// End of the last exported window of each tspoller, persisted between runs
+type BackfillState = BTreeMap<String, DateTime<Utc>>;
+
+/// Exports every window of the selected timeseries pollers between `from` and
+/// `to`, oldest first, using the same queries as the running pollers. Queries
+/// are rate limited to `queries_per_minute`. With a state file, windows that an
+/// earlier run already exported are skipped, so a failed backfill can be
+/// resumed by running the same command again.
+pub async fn run(
+    args: &BackfillArgs,
+    client: &CoalescingClient,
+    config: &GlobalConfig,
+    enricher_map: &HashMap<String, Arc<AttributeEnricher>>,
+    exporter_map: HashMap<String, Sender<ExportRequest>>,
+    merge_handles: Vec<JoinHandle<()>>,
+) -> Result<()> {
+    if args.queries_per_minute == 0 {
+        bail!("--queries-per-minute must be greater than 0");
+    }
+    let tspollers = select_tspollers(config, &args.tspollers)?;
+    let mut state = match &args.state_file {
+        Some(path) => load_state(path)?,
+        None => BackfillState::new(),
+    };
+
+    let mut rate_limit = time::interval(Duration::from_secs(60) / args.queries_per_minute);
+    rate_limit.set_missed_tick_behavior(MissedTickBehavior::Delay);
+
+    let mut result = Ok(());
+    for tspoller in tspollers {
+        let to = args
+            .to
+            .unwrap_or_else(|| Utc::now() - chrono::Duration::seconds(tspoller.lag() as i64));
+        let windows = pending_windows(&state, &tspoller, args.from, to);
+        info!(
+            "Backfill of TSPoller '{}': {} windows from {} to {}",
+            tspoller.name,
+            windows.len(),
+            args.from,
+            to
+        );
+
+        let enrichers = attribute_enricher::resolve_enrichers(
+            tspoller.enrichers.as_deref().unwrap_or_default(),
+            enricher_map,
+        );
+        let router = metric_merger::resolve_exporters(
+            tspoller.exporters.as_deref().unwrap_or_default(),
+            &exporter_map,
+        )?;
+
+        for window in windows {
+            let mut attempt = 1;
+            let batch = loop {
+                rate_limit.tick().await;
+                match intersight_poller::run_intersight_tspoller_window(
+                    client, &tspoller, &enrichers, window,
+                )
+                .await
+                {
+                    Ok(batch) => break Ok(batch),
+                    Err(err) if attempt < MAX_ATTEMPTS => {
+                        warn!(
+                            "Backfill of TSPoller '{}': window {} failed (attempt {} of {}): {}",
+                            tspoller.name,
+                            window.interval(),
+                            attempt,
+                            MAX_ATTEMPTS,
+                            err
+                        );
+                        attempt += 1;
+                    }
+                    Err(err) => break Err(err),
+                }
+            };
+
+            let exported = match batch {
+                Ok(batch) => {
+                    export_window(
+                        &router,
+                        batch,
+                        &mut state,
+                        &tspoller.name,
+                        window,
+                        args.state_file.as_deref(),
+                    )
+                    .await
+                }
+                Err(err) => Err(err),
+            };
+            if let Err(err) = exported {
+                result = Err(err).with_context(|| {
+                    format!(
+                        "Backfill of TSPoller '{}' failed at window {}; run the same command \
+                         again to resume",
+                        tspoller.name,
+                        window.interval()
+                    )
+                });
+                break;
+            }
+        }
+
+        if result.is_err() {
+            break;
+        }
+        info!("Backfill of TSPoller '{}' complete", tspoller.name);
+    }
+
+    // Wait until everything exported so far has been sent, even on failure
+    drop(exporter_map);
+    for merge_handle in merge_handles {
+        merge_handle.await?;
+    }
+
+    result
+}
+
+/// The windows of `tspoller` between `from` and `to` that haven't been exported
+/// according to `state`
+fn pending_windows(
+    state: &BackfillState,
+    tspoller: &TSPollerConfig,
+    from: DateTime<Utc>,
+    to: DateTime<Utc>,
+) -> Vec<QueryWindow> {
+    QueryWindow::range(from, to, tspoller.window(), tspoller.alignment())
+        .into_iter()
+        .filter(|window| {
+            state
+                .get(&tspoller.name)
+                .is_none_or(|end| window.end > *end)
+        })
+        .collect()
+}
+
+/// Sends the batch of a window to the exporters and, once every exporter has
+/// exported it, records the window in the state so a resumed backfill skips
+/// it. A window that failed to export is retried by the next run.
+async fn export_window(
+    router: &MetricRouter,
+    batch: IntersightMetricBatch,
+    state: &mut BackfillState,
+    tspoller: &str,
+    window: QueryWindow,
+    state_file: Option<&str>,
+) -> Result<()> {
+    router.deliver(batch).await?;
+    state.insert(tspoller.to_string(), window.end);
+    if let Some(path) = state_file {
+        save_state(path, state)?;
+    }
+    Ok(())
+}
+
+/// Returns the tspollers with the given names, or all of them if no names are
+/// given
+fn select_tspollers(config: &GlobalConfig, names: &[String]) -> Result<Vec<TSPollerConfig>> {
+    let tspollers = config.tspollers.clone().unwrap_or_default();
+    if names.is_empty() {
+        if tspollers.is_empty() {
+            bail!("No tspollers configured");
+        }
+        return Ok(tspollers);
+    }
+
+    let mut selected = vec![];
+    for name in names {
+        match tspollers.iter().find(|t| &t.name == name) {
+            Some(tspoller) => selected.push(tspoller.clone()),
+            None => bail!("Unknown tspoller '{}'", name),
+        }
+    }
+    Ok(selected)
+}
+
+fn load_state(path: &str) -> Result<BackfillState> {
+    match fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents)
+            .with_context(|| format!("Invalid backfill state file {}", path)),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BackfillState::new()),
+        Err(err) => Err(err).with_context(|| format!("Unable to read {}", path)),
+    }
+}
+
+fn save_state(path: &str, state: &BackfillState) -> Result<()> {
+    // Write to a temporary file first so an interrupted write can't leave a
+    // truncated state file behind
+    let tmp_path = format!("{}.tmp", path);
+    fs::write(&tmp_path, serde_json::to_string_pretty(state)?)
+        .with_context(|| format!("Unable to write {}", tmp_path))?;
+    fs::rename(&tmp_path, path).with_context(|| format!("Unable to write {}", path))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_state_round_trip() {
+        let dir =
+            std::env::temp_dir().join(format!("intersight_otel_backfill_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        let path = path.to_str().unwrap();
+
+        // A missing state file means nothing has been exported yet
+        assert!(load_state(path).unwrap().is_empty());
+
+        let mut state = BackfillState::new();
+        state.insert("power".to_string(), "2025-01-15T14:10:00Z".parse().unwrap());
+        save_state(path, &state).unwrap();
+        assert_eq!(load_state(path).unwrap(), state);
+
+        fs::write(path, "not json").unwrap();
+        assert!(load_state(path).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_resume_after_failed_export() {
+        let dir = std::env::temp_dir().join(format!(
+            "intersight_otel_backfill_resume_{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state.json");
+        let path = path.to_str().unwrap();
+
+        let config = GlobalConfig::from_toml_str(
+            r#"
+            key_file = "key.pem"
+            key_id = "id"
+
+            [[tspollers]]
+            name = "power"
+            datasource = "physicalentities"
+            dimensions = []
+            field_names = []
+            aggregations = []
+            window = 600
+            "#,
+        )
+        .unwrap();
+        let tspoller = &config.tspollers.as_ref().unwrap()[0];
+        let time = |h, m| Utc.with_ymd_and_hms(2025, 1, 15, h, m, 0).unwrap();
+        let (from, to) = (time(14, 0), time(14, 20));
+
+        // An exporter that fails the first export and accepts the others
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<ExportRequest>(1);
+        tokio::spawn(async move {
+            let mut first = true;
+            while let Some(request) = rx.recv().await {
+                let result = match first {
+                    true => Err(anyhow::anyhow!("collector unavailable")),
+                    false => Ok(()),
+                };
+                first = false;
+                let _ = request.ack.unwrap().send(result);
+            }
+        });
+        let exporters = HashMap::from([("otlp".to_string(), tx)]);
+        let router = metric_merger::resolve_exporters(&[], &exporters).unwrap();
+
+        let mut state = load_state(path).unwrap();
+        let windows = pending_windows(&state, tspoller, from, to);
+        assert_eq!(windows.len(), 2);
+
+        // A window that failed to export isn't recorded, so it is retried
+        assert!(
+            export_window(&router, vec![], &mut state, "power", windows[0], Some(path))
+                .await
+                .is_err()
+        );
+        let mut state = load_state(path).unwrap();
+        assert_eq!(pending_windows(&state, tspoller, from, to), windows);
+
+        export_window(&router, vec![], &mut state, "power", windows[0], Some(path))
+            .await
+            .unwrap();
+        let state = load_state(path).unwrap();
+        assert_eq!(pending_windows(&state, tspoller, from, to), windows[1..]);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_select_tspollers() {
+        let config = GlobalConfig::from_toml_str(
+            r#"
+            key_file = "key.pem"
+            key_id = "id"
+
+            [[tspollers]]
+            name = "power"
+            datasource = "physicalentities"
+            dimensions = []
+            field_names = []
+            aggregations = []
+
+            [[tspollers]]
+            name = "fans"
+            datasource = "physicalentities"
+            dimensions = []
+            field_names = []
+            aggregations = []
+            "#,
+        )
+        .unwrap();
+
+        let names = |selected: Vec<TSPollerConfig>| -> Vec<String> {
+            selected.into_iter().map(|t| t.name).collect()
+        };
+        assert_eq!(
+            names(select_tspollers(&config, &[]).unwrap()),
+            ["power", "fans"]
+        );
+        assert_eq!(
+            names(select_tspollers(&config, &["fans".to_string()]).unwrap()),
+            ["fans"]
+        );
+        assert!(select_tspollers(&config, &["nope".to_string()]).is_err());
+    }
+}
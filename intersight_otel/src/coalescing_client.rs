@@ -0,0 +1,278 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use intersight_api::{Client, IntersightError};
+use serde_json::Value;
+use tokio::sync::OnceCell;
+
+pub type Response = Result<Value, Arc<IntersightError>>;
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct RequestKey {
+    method: &'static str,
+    path: String,
+    body: String,
+}
+
+/// Request counters of a [`CoalescingClient`], for tuning the cache TTL
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct ClientStats {
+    /// Requests made by pollers and enrichers
+    pub requests: u64,
+    /// Requests that waited for an identical request already in flight
+    pub coalesced: u64,
+    /// Requests answered from the response cache
+    pub cache_hits: u64,
+}
+
+#[derive(Default)]
+struct Inner {
+    coalesce: bool,
+    cache_ttl: Option<Duration>,
+    in_flight: Mutex<HashMap<RequestKey, Arc<OnceCell<Response>>>>,
+    cache: Mutex<HashMap<RequestKey, (Instant, Value)>>,
+    requests: AtomicU64,
+    coalesced: AtomicU64,
+    cache_hits: AtomicU64,
+}
+
+/// Wraps the Intersight API client so that identical requests (same method,
+/// path and body) made at the same time by different pollers and enrichers
+/// result in a single API call. Successful responses can optionally be cached
+/// for `cache_ttl` so that requests shortly after each other are shared too.
+#[derive(Clone)]
+pub struct CoalescingClient {
+    client: Client,
+    inner: Arc<Inner>,
+}
+
+impl CoalescingClient {
+    pub fn new(client: Client, coalesce: bool, cache_ttl: Option<Duration>) -> Self {
+        CoalescingClient {
+            client,
+            inner: Arc::new(Inner {
+                coalesce,
+                cache_ttl: cache_ttl.filter(|ttl| !ttl.is_zero()),
+                ..Default::default()
+            }),
+        }
+    }
+
+    pub async fn get(&self, path: &str) -> Response {
+        let key = RequestKey {
+            method: "GET",
+            path: path.to_string(),
+            body: String::new(),
+        };
+        self.inner.call(key, || self.client.get(path)).await
+    }
+
+    pub async fn post(&self, path: &str, body: Value) -> Response {
+        let key = RequestKey {
+            method: "POST",
+            path: path.to_string(),
+            body: body.to_string(),
+        };
+        self.inner.call(key, || self.client.post(path, body)).await
+    }
+
+    pub fn stats(&self) -> ClientStats {
+        self.inner.stats()
+    }
+}
+
+impl Inner {
+    async fn call<F, Fut>(&self, key: RequestKey, request: F) -> Response
+    where
+        F: FnOnce() -> Fut,
+        Fut: Future<Output = Result<Value, IntersightError>>,
+    {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(value) = self.cached(&key) {
+            self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(value);
+        }
+
+        if !self.coalesce {
+            let response = request().await.map_err(Arc::new);
+            self.store(&key, &response);
+            return response;
+        }
+
+        let cell = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(key.clone())
+            .or_default()
+            .clone();
+
+        let mut called = false;
+        let response = cell
+            .get_or_init(|| {
+                called = true;
+                async { request().await.map_err(Arc::new) }
+            })
+            .await
+            .clone();
+
+        if called {
+            self.store(&key, &response);
+        } else {
+            self.coalesced.fetch_add(1, Ordering::Relaxed);
+        }
+
+        // Later requests must make a new call (or hit the cache), so forget
+        // this one unless it has already been replaced
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight.get(&key).is_some_and(|c| Arc::ptr_eq(c, &cell)) {
+            in_flight.remove(&key);
+        }
+
+        response
+    }
+
+    fn cached(&self, key: &RequestKey) -> Option<Value> {
+        let ttl = self.cache_ttl?;
+        let cache = self.cache.lock().unwrap();
+        cache
+            .get(key)
+            .filter(|(stored_at, _)| stored_at.elapsed() < ttl)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn store(&self, key: &RequestKey, response: &Response) {
+        let (Some(ttl), Ok(value)) = (self.cache_ttl, response) else {
+            return;
+        };
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_, (stored_at, _)| stored_at.elapsed() < ttl);
+        cache.insert(key.clone(), (Instant::now(), value.clone()));
+    }
+
+    fn stats(&self) -> ClientStats {
+        ClientStats {
+            requests: self.requests.load(Ordering::Relaxed),
+            coalesced: self.coalesced.load(Ordering::Relaxed),
+            cache_hits: self.cache_hits.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn key(path: &str) -> RequestKey {
+        RequestKey {
+            method: "GET",
+            path: path.to_string(),
+            body: String::new(),
+        }
+    }
+
+    fn inner(coalesce: bool, cache_ttl: Option<Duration>) -> Arc<Inner> {
+        Arc::new(Inner {
+            coalesce,
+            cache_ttl,
+            ..Default::default()
+        })
+    }
+
+    async fn slow_request(calls: Arc<AtomicU64>) -> Result<Value, IntersightError> {
+        calls.fetch_add(1, Ordering::SeqCst);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        Ok(json!({"Count": 1}))
+    }
+
+    #[tokio::test]
+    async fn test_identical_requests_are_coalesced() {
+        let inner = inner(true, None);
+        let calls = Arc::new(AtomicU64::new(0));
+
+        let mut tasks = vec![];
+        for _ in 0..3 {
+            let (inner, calls) = (inner.clone(), calls.clone());
+            tasks.push(tokio::spawn(async move {
+                inner
+                    .call(key("api/v1/cond/Alarms"), || slow_request(calls))
+                    .await
+            }));
+        }
+        for task in tasks {
+            assert_eq!(task.await.unwrap().unwrap(), json!({"Count": 1}));
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            inner.stats(),
+            ClientStats {
+                requests: 3,
+                coalesced: 2,
+                cache_hits: 0
+            }
+        );
+        assert!(inner.in_flight.lock().unwrap().is_empty());
+
+        // Without a cache, a later request makes a new call
+        let calls_clone = calls.clone();
+        inner
+            .call(key("api/v1/cond/Alarms"), || slow_request(calls_clone))
+            .await
+            .unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[tokio::test]
+    async fn test_different_requests_are_not_coalesced() {
+        let inner = inner(true, None);
+        let calls = Arc::new(AtomicU64::new(0));
+
+        let (a, b) = tokio::join!(
+            inner.call(key("api/v1/a"), || slow_request(calls.clone())),
+            inner.call(key("api/v1/b"), || slow_request(calls.clone())),
+        );
+
+        assert!(a.is_ok() && b.is_ok());
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+        assert_eq!(inner.stats().coalesced, 0);
+    }
+
+    #[tokio::test]
+    async fn test_cached_responses() {
+        let inner = inner(false, Some(Duration::from_secs(60)));
+        let calls = Arc::new(AtomicU64::new(0));
+
+        for _ in 0..2 {
+            let calls = calls.clone();
+            inner
+                .call(key("api/v1/a"), || slow_request(calls))
+                .await
+                .unwrap();
+        }
+
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        assert_eq!(inner.stats().cache_hits, 1);
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_not_cached() {
+        let inner = inner(true, Some(Duration::from_secs(60)));
+
+        for _ in 0..2 {
+            let response = inner
+                .call(key("api/v1/a"), || async {
+                    Err(IntersightError::ApiError(500))
+                })
+                .await;
+            assert!(response.is_err());
+        }
+
+        assert_eq!(inner.stats().cache_hits, 0);
+    }
+}
@@ -1,4 +1,6 @@
-use clap::Parser;
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand};
 use config::{Config, ConfigError, Environment, File};
 use serde::Deserialize;
 use serde_json::Value;
@@ -21,6 +23,54 @@ pub struct AttributeEnricherConfig {
     pub result_mappings: Vec<ResultMappingConfig>,
 }
 
+#[derive(Debug, Deserialize, Clone, Default)]
+#[allow(unused)]
+pub struct ExporterConfig {
+    pub name: String,
+    #[serde(rename = "type")]
+    pub exporter_type: String,
+    pub endpoint: Option<String>,
+    pub listen_address: Option<String>,
+    series_ttl: Option<u64>,
+    pub format: Option<String>,
+    pub path: Option<String>,
+    max_file_size: Option<u64>,
+    max_files: Option<usize>,
+}
+
+impl ExporterConfig {
+    pub fn series_ttl(&self) -> u64 {
+        self.series_ttl.unwrap_or(900)
+    }
+
+    pub fn max_file_size(&self) -> u64 {
+        self.max_file_size.unwrap_or(100 * 1024 * 1024)
+    }
+
+    pub fn max_files(&self) -> usize {
+        self.max_files.unwrap_or(5)
+    }
+
+    /// In dry-run mode every exporter is replaced with a stdout exporter of the
+    /// same name, so poller routing is unchanged but nothing is sent to a
+    /// collector, served on a port or written to disk.
+    pub fn dry_run(self) -> ExporterConfig {
+        if self.exporter_type == "stdout" {
+            return self;
+        }
+        ExporterConfig::stdout(self.name)
+    }
+
+    /// A stdout exporter with the default table format
+    pub fn stdout(name: String) -> ExporterConfig {
+        ExporterConfig {
+            name,
+            exporter_type: "stdout".to_string(),
+            ..Default::default()
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Clone)]
 #[allow(unused)]
 pub struct GlobalConfig {
@@ -28,27 +78,89 @@ pub struct GlobalConfig {
     key_id: String,
     pub intersight_host: Option<String>,
     pub intersight_accept_invalid_certs: Option<bool>,
-    pub otel_collector_endpoint: String,
+    pub otel_collector_endpoint: Option<String>,
+    pub prometheus_listen_address: Option<String>,
+    prometheus_series_ttl: Option<u64>,
+    coalesce_requests: Option<bool>,
+    response_cache_ttl: Option<u64>,
     pub pollers: Option<Vec<PollerConfig>>,
     pub tspollers: Option<Vec<TSPollerConfig>>,
     pub enrichers: Option<Vec<AttributeEnricherConfig>>,
+    pub exporters: Option<Vec<ExporterConfig>>,
 }
 
 impl GlobalConfig {
-    pub fn new() -> Result<Self, ConfigError> {
-        let args = Args::parse();
-
+    pub fn new(config_file: &str) -> Result<Self, ConfigError> {
         let c = Config::builder()
-            .add_source(File::with_name(&args.config_file))
+            .add_source(File::with_name(config_file))
             .add_source(Environment::with_prefix("intersight_otel"))
             .build()?;
 
         c.try_deserialize()
     }
 
+    #[cfg(test)]
+    pub fn from_toml_str(toml: &str) -> Result<Self, ConfigError> {
+        Config::builder()
+            .add_source(File::from_str(toml, config::FileFormat::Toml))
+            .build()?
+            .try_deserialize()
+    }
+
     pub fn key_id(&self) -> &str {
         self.key_id.trim()
     }
+
+    pub fn coalesce_requests(&self) -> bool {
+        self.coalesce_requests.unwrap_or(true)
+    }
+
+    pub fn response_cache_ttl(&self) -> u64 {
+        self.response_cache_ttl.unwrap_or(0)
+    }
+
+    /// Reads the `query_file` of every tspoller into its `query`, so the file is
+    /// read once when the configuration is loaded rather than on every poll
+    pub fn load_query_files(&mut self) -> anyhow::Result<()> {
+        for tspoller in self.tspollers.iter_mut().flatten() {
+            if let (None, Some(path)) = (&tspoller.query, &tspoller.query_file) {
+                let query = std::fs::read_to_string(path).with_context(|| {
+                    format!(
+                        "TSPoller '{}': unable to read query_file {}",
+                        tspoller.name, path
+                    )
+                })?;
+                tspoller.query = Some(query);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns all configured exporters. The top-level `otel_collector_endpoint`
+    /// and `prometheus_listen_address` settings are shorthands for exporters
+    /// named "default" and "prometheus" respectively.
+    pub fn exporter_configs(&self) -> Vec<ExporterConfig> {
+        let mut exporters = vec![];
+        if let Some(endpoint) = &self.otel_collector_endpoint {
+            exporters.push(ExporterConfig {
+                name: "default".to_string(),
+                exporter_type: "otlp".to_string(),
+                endpoint: Some(endpoint.clone()),
+                ..Default::default()
+            });
+        }
+        if let Some(listen_address) = &self.prometheus_listen_address {
+            exporters.push(ExporterConfig {
+                name: "prometheus".to_string(),
+                exporter_type: "prometheus".to_string(),
+                listen_address: Some(listen_address.clone()),
+                series_ttl: self.prometheus_series_ttl,
+                ..Default::default()
+            });
+        }
+        exporters.extend(self.exporters.clone().unwrap_or_default());
+        exporters
+    }
 }
 
 pub type OTelAttributes = HashMap<String, String>;
@@ -59,26 +171,214 @@ pub struct PollerConfig {
     pub api_query: String,
     pub api_method: Option<String>,
     pub api_body: Option<String>,
+    #[serde(default)]
     pub aggregator: String,
-    pub aggregator_options: Option<HashMap<String, String>>,
+    pub aggregator_options: Option<HashMap<String, Value>>,
     pub name: String,
     pub otel_attributes: Option<HashMap<String, String>>,
+    pub datapoint_attributes: Option<Vec<String>>,
     pub enrichers: Option<Vec<String>>,
+    pub exporters: Option<Vec<String>>,
+    pub page_size: Option<u64>,
+    pub metrics: Option<Vec<PollerMetricConfig>>,
+    pub unit: Option<String>,
+    pub description: Option<String>,
+    pub kind: Option<String>,
+    pub temporality: Option<String>,
+    pub monotonic: Option<bool>,
 
     interval: Option<u64>, // interval is private with a getter because it might change to human strings like "5m" in the future
 }
 
+/// One of several metrics computed from the same API response of a poller
+#[derive(Debug, Deserialize, Clone)]
+pub struct PollerMetricConfig {
+    pub name: String,
+    pub aggregator: String,
+    pub aggregator_options: Option<HashMap<String, Value>>,
+    pub unit: Option<String>,
+    pub description: Option<String>,
+    pub otel_attributes: Option<HashMap<String, String>>,
+    pub kind: Option<String>,
+    pub temporality: Option<String>,
+    pub monotonic: Option<bool>,
+}
+
 impl PollerConfig {
     pub fn interval(&self) -> u64 {
         self.interval.unwrap_or(10)
     }
+
+    /// Returns a copy of this poller's config for each entry in `metrics`, with
+    /// the name and aggregator of that metric and the poller's and metric's
+    /// `otel_attributes` merged. The metric's unit, description and kind
+    /// default to the poller's.
+    pub fn metric_configs(&self) -> Vec<PollerConfig> {
+        self.metrics
+            .iter()
+            .flatten()
+            .map(|metric| {
+                let mut otel_attributes = self.otel_attributes.clone().unwrap_or_default();
+                otel_attributes.extend(metric.otel_attributes.clone().unwrap_or_default());
+                PollerConfig {
+                    name: metric.name.clone(),
+                    aggregator: metric.aggregator.clone(),
+                    aggregator_options: metric.aggregator_options.clone(),
+                    otel_attributes: Some(otel_attributes),
+                    metrics: None,
+                    unit: metric.unit.clone().or_else(|| self.unit.clone()),
+                    description: metric
+                        .description
+                        .clone()
+                        .or_else(|| self.description.clone()),
+                    kind: metric.kind.clone().or_else(|| self.kind.clone()),
+                    temporality: metric
+                        .temporality
+                        .clone()
+                        .or_else(|| self.temporality.clone()),
+                    monotonic: metric.monotonic.or(self.monotonic),
+                    ..self.clone()
+                }
+            })
+            .collect()
+    }
 }
 
 #[derive(Parser, Debug)]
 #[clap(author, version, about, long_about = None)]
-struct Args {
+pub struct Args {
     #[clap(short, long, value_parser, default_value_t = String::from("intersight_otel"))]
-    config_file: String,
+    pub config_file: String,
+
+    /// Print metrics to stdout instead of sending them to any exporter
+    #[clap(long)]
+    pub dry_run: bool,
+
+    /// Run every poller once, export the results and exit (non-zero if any poller or export failed)
+    #[clap(long)]
+    pub once: bool,
+
+    #[clap(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Validate the configuration file and report all errors found
+    Check,
+    /// Export historical timeseries data for a time range
+    Backfill(BackfillArgs),
+    /// List telemetry datasources with their dimensions and metric columns
+    Discover(DiscoverArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct BackfillArgs {
+    /// Start of the time range (RFC 3339, e.g. 2025-01-15T00:00:00Z)
+    #[clap(long)]
+    pub from: DateTime<Utc>,
+
+    /// End of the time range (RFC 3339); defaults to now minus each tspoller's lag
+    #[clap(long)]
+    pub to: Option<DateTime<Utc>>,
+
+    /// Name of a tspoller to backfill; may be repeated (default: all tspollers)
+    #[clap(long = "tspoller")]
+    pub tspollers: Vec<String>,
+
+    /// Maximum number of Druid queries per minute
+    #[clap(long, default_value_t = 60)]
+    pub queries_per_minute: u32,
+
+    /// File recording the last exported window of each tspoller, so that an
+    /// interrupted backfill resumes where it stopped when run again
+    #[clap(long)]
+    pub state_file: Option<String>,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DiscoverArgs {
+    /// Datasource to probe; may be repeated (default: only the well-known
+    /// datasources PhysicalEntities, NetworkInterfaces and hx)
+    #[clap(long = "datasource")]
+    pub datasources: Vec<String>,
+
+    /// Print a skeleton [[tspollers]] block for each datasource instead of listing it
+    #[clap(long)]
+    pub toml: bool,
+}
+
+/// A field of the Druid results exported by a timeseries poller, given either
+/// as just its name or as a table with the name and how to export it
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[serde(from = "FieldConfigEntry")]
+pub struct FieldConfig {
+    pub name: String,
+    pub unit: Option<String>,
+    pub description: Option<String>,
+    pub kind: Option<String>,
+    pub temporality: Option<String>,
+    pub monotonic: Option<bool>,
+    /// Histogram bucket boundaries, matching the split points of the Druid
+    /// histogram post-aggregation
+    pub bounds: Option<Vec<f64>>,
+    /// Summary quantiles, matching the fractions of the Druid quantiles
+    /// post-aggregation
+    pub quantiles: Option<Vec<f64>>,
+    pub count_field: Option<String>,
+    pub sum_field: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum FieldConfigEntry {
+    Name(String),
+    Field {
+        name: String,
+        unit: Option<String>,
+        description: Option<String>,
+        kind: Option<String>,
+        temporality: Option<String>,
+        monotonic: Option<bool>,
+        bounds: Option<Vec<f64>>,
+        quantiles: Option<Vec<f64>>,
+        count_field: Option<String>,
+        sum_field: Option<String>,
+    },
+}
+
+impl From<FieldConfigEntry> for FieldConfig {
+    fn from(entry: FieldConfigEntry) -> Self {
+        match entry {
+            FieldConfigEntry::Name(name) => FieldConfig {
+                name,
+                ..Default::default()
+            },
+            FieldConfigEntry::Field {
+                name,
+                unit,
+                description,
+                kind,
+                temporality,
+                monotonic,
+                bounds,
+                quantiles,
+                count_field,
+                sum_field,
+            } => FieldConfig {
+                name,
+                unit,
+                description,
+                kind,
+                temporality,
+                monotonic,
+                bounds,
+                quantiles,
+                count_field,
+                sum_field,
+            },
+        }
+    }
 }
 
 #[derive(Debug, Deserialize, Clone)]
@@ -86,17 +386,31 @@ struct Args {
 pub struct TSPollerConfig {
     pub name: String,
     pub datasource: String,
+    #[serde(default)]
     pub dimensions: Vec<String>,
-    pub field_names: Vec<String>,
+    pub field_names: Vec<FieldConfig>,
     pub filter: Option<Value>,
     pub aggregations: Option<Value>,
     pub post_aggregations: Option<Value>,
     poller_type: Option<String>,
     interval: Option<u64>,
+    lag: Option<u64>,
+    window: Option<u64>,
+    alignment: Option<u64>,
+    adaptive_lag: Option<bool>,
+    pub granularity: Option<Value>,
+    query_type: Option<String>,
+    pub metric: Option<Value>,
+    pub threshold: Option<u64>,
+    pub limit: Option<u64>,
+    pub query: Option<String>,
+    pub query_file: Option<String>,
 
     pub otel_attributes: Option<HashMap<String, String>>,
     pub otel_dimension_to_attribute_map: Option<HashMap<String, String>>,
+    pub datapoint_attributes: Option<Vec<String>>,
     pub enrichers: Option<Vec<String>>,
+    pub exporters: Option<Vec<String>>,
 }
 
 pub enum TSPollerType {
@@ -104,8 +418,39 @@ pub enum TSPollerType {
     Sum,
 }
 
+/// The Druid query type of a timeseries poller
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TSQueryType {
+    GroupBy,
+    Timeseries,
+    TopN,
+    Scan,
+}
+
+impl TSQueryType {
+    /// The query type for a Druid `queryType`
+    pub fn from_name(name: &str) -> Option<TSQueryType> {
+        match name {
+            "groupBy" => Some(TSQueryType::GroupBy),
+            "timeseries" => Some(TSQueryType::Timeseries),
+            "topN" => Some(TSQueryType::TopN),
+            "scan" => Some(TSQueryType::Scan),
+            _ => None,
+        }
+    }
+
+    /// The Intersight telemetry endpoint for this query type
+    pub fn endpoint(&self) -> &'static str {
+        match self {
+            TSQueryType::GroupBy => "api/v1/telemetry/GroupBys",
+            TSQueryType::Timeseries => "api/v1/telemetry/TimeSeries",
+            TSQueryType::TopN => "api/v1/telemetry/TopNs",
+            TSQueryType::Scan => "api/v1/telemetry/Scans",
+        }
+    }
+}
+
 impl TSPollerConfig {
-    #[allow(unused)]
     pub fn poller_type(&self) -> TSPollerType {
         if let Some(t) = &self.poller_type {
             return match t.as_str() {
@@ -120,20 +465,73 @@ impl TSPollerConfig {
     pub fn interval(&self) -> u64 {
         self.interval.unwrap_or(10)
     }
+
+    /// Seconds between the end of the queried window and now, so that Druid
+    /// has all the data for the window
+    pub fn lag(&self) -> u64 {
+        self.lag.unwrap_or(900)
+    }
+
+    /// Length of the queried window in seconds
+    pub fn window(&self) -> u64 {
+        self.window.unwrap_or(600)
+    }
+
+    /// Windows end on a multiple of this many seconds since the Unix epoch
+    pub fn alignment(&self) -> u64 {
+        self.alignment.unwrap_or_else(|| self.window())
+    }
+
+    /// Whether to query Druid's TimeBoundary for the latest complete data
+    /// instead of using a fixed lag
+    pub fn adaptive_lag(&self) -> bool {
+        self.adaptive_lag.unwrap_or(false)
+    }
+
+    /// The Druid query type, `None` if `query_type` isn't one of `groupBy`,
+    /// `timeseries`, `topN` or `scan`
+    pub fn query_type(&self) -> Option<TSQueryType> {
+        TSQueryType::from_name(self.query_type_name())
+    }
+
+    /// The kind of the metrics of a field: its own `kind`, otherwise `sum` for
+    /// all fields of pollers with `poller_type = "sum"` and `gauge` for others
+    pub fn field_kind<'a>(&self, field: &'a FieldConfig) -> &'a str {
+        match (&field.kind, self.poller_type()) {
+            (Some(kind), _) => kind,
+            (None, TSPollerType::Sum) => "sum",
+            (None, TSPollerType::LastValue) => "gauge",
+        }
+    }
+
+    pub fn query_type_name(&self) -> &str {
+        self.query_type.as_deref().unwrap_or("groupBy")
+    }
 }
 
 pub trait OTelAttributeProvider {
     fn otel_attributes(&self) -> OTelAttributes;
+
+    /// Attributes exported on each data point rather than on the resource
+    fn datapoint_attributes(&self) -> &[String];
 }
 
 impl OTelAttributeProvider for PollerConfig {
     fn otel_attributes(&self) -> OTelAttributes {
         self.otel_attributes.clone().unwrap_or_default()
     }
+
+    fn datapoint_attributes(&self) -> &[String] {
+        self.datapoint_attributes.as_deref().unwrap_or_default()
+    }
 }
 
 impl OTelAttributeProvider for TSPollerConfig {
     fn otel_attributes(&self) -> OTelAttributes {
         self.otel_attributes.clone().unwrap_or_default()
     }
+
+    fn datapoint_attributes(&self) -> &[String] {
+        self.datapoint_attributes.as_deref().unwrap_or_default()
+    }
 }
@@ -0,0 +1,1207 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt;
+
+use anyhow::{bail, Result};
+use chrono::Utc;
+use serde_json::Value;
+
+use crate::config::{
+    ExporterConfig, FieldConfig, GlobalConfig, PollerConfig, TSPollerConfig, TSQueryType,
+};
+use crate::intersight_poller::{self, timeseries_poller, MetricKind, QueryWindow};
+use crate::metric_merger;
+use crate::ucum;
+
+/// A single problem found while validating the configuration, identifying the
+/// offending section (e.g. `pollers[2] 'intersight.vm.count'`) and field.
+#[derive(Debug, PartialEq)]
+pub struct ConfigIssue {
+    pub location: String,
+    pub field: String,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.location, self.field, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Checker {
+    issues: Vec<ConfigIssue>,
+    warnings: Vec<ConfigIssue>,
+}
+
+impl Checker {
+    fn error(&mut self, location: &str, field: &str, message: impl Into<String>) {
+        self.issues.push(ConfigIssue {
+            location: location.to_string(),
+            field: field.to_string(),
+            message: message.into(),
+        });
+    }
+
+    fn warn(&mut self, location: &str, field: &str, message: impl Into<String>) {
+        self.warnings.push(ConfigIssue {
+            location: location.to_string(),
+            field: field.to_string(),
+            message: message.into(),
+        });
+    }
+}
+
+/// Runs the `check` subcommand: validates the configuration, prints every
+/// problem and warning found and fails if there were any problems.
+pub fn run(config: &GlobalConfig) -> Result<()> {
+    let checker = check(config);
+    for warning in &checker.warnings {
+        println!("warning: {}", warning);
+    }
+    if checker.issues.is_empty() {
+        println!("Configuration OK");
+        return Ok(());
+    }
+
+    for issue in &checker.issues {
+        println!("{}", issue);
+    }
+    bail!("{} configuration error(s) found", checker.issues.len());
+}
+
+/// The problems found by [`check`], without the warnings
+#[cfg(test)]
+pub fn check_config(config: &GlobalConfig) -> Vec<ConfigIssue> {
+    check(config).issues
+}
+
+/// Validates the whole configuration and returns every problem and warning
+/// found, rather than stopping at the first one.
+fn check(config: &GlobalConfig) -> Checker {
+    let mut checker = Checker::default();
+
+    check_key_file(&mut checker, config);
+
+    let exporters = config.exporter_configs();
+    if exporters.is_empty() {
+        checker.error(
+            "global",
+            "exporters",
+            "no exporter configured: set otel_collector_endpoint, prometheus_listen_address or add [[exporters]]",
+        );
+    }
+    let mut exporter_names = HashSet::new();
+    for (i, exporter) in exporters.iter().enumerate() {
+        let location = format!("exporters[{}] '{}'", i, exporter.name);
+        if !exporter_names.insert(exporter.name.as_str()) {
+            checker.error(&location, "name", "duplicate exporter name");
+        }
+        check_exporter(&mut checker, &location, exporter);
+    }
+
+    let mut enricher_names = HashSet::new();
+    for (i, enricher) in config.enrichers.iter().flatten().enumerate() {
+        let location = format!("enrichers[{}] '{}'", i, enricher.name);
+        if !enricher_names.insert(enricher.name.as_str()) {
+            checker.error(&location, "name", "duplicate enricher name");
+        }
+        if let Some(pattern) = &enricher.source_value_regex {
+            if let Err(err) = regex::Regex::new(pattern) {
+                checker.error(
+                    &location,
+                    "source_value_regex",
+                    format!("invalid regex '{}': {}", pattern, err),
+                );
+            }
+        }
+        if !enricher.query_template.contains("{value}") {
+            checker.error(
+                &location,
+                "query_template",
+                "template does not contain the {value} placeholder",
+            );
+        }
+        for mapping in &enricher.result_mappings {
+            if let Err(err) = serde_json_path::JsonPath::parse(&mapping.result_field) {
+                checker.error(
+                    &location,
+                    "result_mappings",
+                    format!("invalid JSONPath '{}': {}", mapping.result_field, err),
+                );
+            }
+        }
+    }
+
+    // Metrics are only ambiguous if they share a name and the same set of attributes
+    let mut metric_keys: HashMap<(String, BTreeMap<String, String>), String> = HashMap::new();
+
+    for (i, poller) in config.pollers.iter().flatten().enumerate() {
+        let location = format!("pollers[{}] '{}'", i, poller.name);
+        check_poller(&mut checker, &location, poller);
+        check_references(
+            &mut checker,
+            &location,
+            poller.enrichers.as_deref(),
+            poller.exporters.as_deref(),
+            &enricher_names,
+            &exporter_names,
+        );
+
+        let metric_configs = match poller.metrics {
+            Some(_) => poller.metric_configs(),
+            None => vec![poller.clone()],
+        };
+        for metric in metric_configs {
+            let key = (
+                metric.name,
+                metric.otel_attributes.into_iter().flatten().collect(),
+            );
+            check_duplicate_metric(&mut checker, &mut metric_keys, &location, key);
+        }
+    }
+
+    for (i, tspoller) in config.tspollers.iter().flatten().enumerate() {
+        let location = format!("tspollers[{}] '{}'", i, tspoller.name);
+        check_tspoller(&mut checker, &location, tspoller);
+        check_references(
+            &mut checker,
+            &location,
+            tspoller.enrichers.as_deref(),
+            tspoller.exporters.as_deref(),
+            &enricher_names,
+            &exporter_names,
+        );
+
+        let mut attributes: BTreeMap<String, String> = tspoller
+            .otel_attributes
+            .clone()
+            .into_iter()
+            .flatten()
+            .collect();
+        for attribute in tspoller
+            .otel_dimension_to_attribute_map
+            .iter()
+            .flatten()
+            .map(|(_, a)| a)
+        {
+            attributes.insert(attribute.clone(), "<dimension>".to_string());
+        }
+        check_datapoint_attributes(&mut checker, &location, config, tspoller, &attributes);
+        // Tspollers that query different data don't produce the same series,
+        // so the query's datasource and filter are part of the key too
+        let mut query_attributes = attributes;
+        query_attributes.insert("<datasource>".to_string(), tspoller.datasource.clone());
+        if let Some(filter) = &tspoller.filter {
+            query_attributes.insert("<filter>".to_string(), filter.to_string());
+        }
+        for field in &tspoller.field_names {
+            let key = (field.name.clone(), query_attributes.clone());
+            check_duplicate_metric(&mut checker, &mut metric_keys, &location, key);
+        }
+    }
+
+    checker
+}
+
+fn check_key_file(checker: &mut Checker, config: &GlobalConfig) {
+    match std::fs::read(&config.key_file) {
+        Ok(pem) => {
+            if let Err(err) = intersight_api::simplesigner::Signer::from_pem(&pem) {
+                checker.error("global", "key_file", err.to_string());
+            }
+        }
+        Err(err) => checker.error(
+            "global",
+            "key_file",
+            format!("unable to read '{}': {}", config.key_file, err),
+        ),
+    }
+}
+
+fn check_exporter(checker: &mut Checker, location: &str, exporter: &ExporterConfig) {
+    if let Err(err) = metric_merger::exporter_spec(exporter) {
+        checker.error(location, err.field, err.message);
+    }
+}
+
+fn check_poller(checker: &mut Checker, location: &str, poller: &PollerConfig) {
+    match &poller.metrics {
+        Some(metrics) => {
+            if !poller.aggregator.is_empty() {
+                checker.error(
+                    location,
+                    "aggregator",
+                    "aggregator must not be set when metrics are configured",
+                );
+            }
+            if metrics.is_empty() {
+                checker.error(location, "metrics", "at least one metric is required");
+            }
+            for (i, metric) in poller.metric_configs().iter().enumerate() {
+                if let Err(err) = intersight_poller::get_aggregator_for_config(metric) {
+                    checker.error(
+                        location,
+                        &format!("metrics[{}].aggregator", i),
+                        err.to_string(),
+                    );
+                }
+                if let Err(err) = intersight_poller::poller_metric_kind(metric) {
+                    checker.error(location, &format!("metrics[{}].kind", i), err.to_string());
+                }
+                check_unit(
+                    checker,
+                    location,
+                    &format!("metrics[{}].unit", i),
+                    &metric.unit,
+                );
+            }
+            check_unit(checker, location, "unit", &poller.unit);
+        }
+        None if poller.aggregator.is_empty() => {
+            checker.error(
+                location,
+                "aggregator",
+                "either aggregator or metrics is required",
+            );
+        }
+        None => {
+            if let Err(err) = intersight_poller::get_aggregator_for_config(poller) {
+                checker.error(location, "aggregator", err.to_string());
+            }
+            if let Err(err) = intersight_poller::poller_metric_kind(poller) {
+                checker.error(location, "kind", err.to_string());
+            }
+            check_unit(checker, location, "unit", &poller.unit);
+        }
+    }
+
+    let method = poller.api_method.as_deref().unwrap_or_default();
+    if !matches!(method, "" | "get" | "post") {
+        checker.error(
+            location,
+            "api_method",
+            format!("unsupported method '{}', expected 'get' or 'post'", method),
+        );
+    }
+
+    if let Some(body) = poller.api_body.as_deref().filter(|b| !b.is_empty()) {
+        if let Err(err) = serde_json::from_str::<Value>(body) {
+            checker.error(location, "api_body", format!("invalid JSON: {}", err));
+        }
+        if method != "post" {
+            checker.warn(
+                location,
+                "api_body",
+                "api_body is only sent when api_method = \"post\"",
+            );
+        }
+    }
+
+    if poller.page_size.is_some() {
+        if let Err(err) = intersight_poller::check_page_size(poller) {
+            checker.error(location, "page_size", err.to_string());
+        }
+        if poller.api_query.contains("$top") || poller.api_query.contains("$skip") {
+            checker.error(
+                location,
+                "page_size",
+                "api_query must not contain $top or $skip when page_size is set",
+            );
+        }
+    }
+
+    if poller.interval() == 0 {
+        checker.error(location, "interval", "interval must be greater than 0");
+    }
+}
+
+fn check_tspoller(checker: &mut Checker, location: &str, tspoller: &TSPollerConfig) {
+    if tspoller.datasource.is_empty() {
+        checker.error(location, "datasource", "datasource must not be empty");
+    }
+    if tspoller.field_names.is_empty() {
+        checker.error(
+            location,
+            "field_names",
+            "at least one field name is required",
+        );
+    }
+    for field in &tspoller.field_names {
+        check_field(checker, location, tspoller, field);
+    }
+    if tspoller.interval() == 0 {
+        checker.error(location, "interval", "interval must be greater than 0");
+    }
+    if tspoller.window() == 0 {
+        checker.error(location, "window", "window must be greater than 0");
+    }
+    if tspoller.alignment() == 0 {
+        checker.error(location, "alignment", "alignment must be greater than 0");
+    }
+    if tspoller.query.is_some() || tspoller.query_file.is_some() {
+        check_query_template(checker, location, tspoller);
+        return;
+    }
+    match &tspoller.granularity {
+        None | Some(Value::String(_)) => {}
+        Some(Value::Object(o)) if o.contains_key("type") => {}
+        Some(other) => checker.error(
+            location,
+            "granularity",
+            format!(
+                "expected a granularity name or an object with a 'type', got {}",
+                other
+            ),
+        ),
+    }
+
+    match tspoller.query_type() {
+        None => checker.error(
+            location,
+            "query_type",
+            format!(
+                "expected one of groupBy, timeseries, topN or scan, got {}",
+                tspoller.query_type_name()
+            ),
+        ),
+        Some(TSQueryType::Timeseries) if !tspoller.dimensions.is_empty() => checker.error(
+            location,
+            "dimensions",
+            "timeseries queries have no dimensions",
+        ),
+        Some(TSQueryType::TopN) => {
+            if tspoller.dimensions.len() != 1 {
+                checker.error(
+                    location,
+                    "dimensions",
+                    "topN queries need exactly one dimension",
+                );
+            }
+            if tspoller.metric.is_none() {
+                checker.error(location, "metric", "metric is required for topN queries");
+            }
+            if tspoller.threshold.is_none() {
+                checker.error(
+                    location,
+                    "threshold",
+                    "threshold is required for topN queries",
+                );
+            }
+        }
+        Some(TSQueryType::Scan)
+            if tspoller.aggregations.is_some() || tspoller.post_aggregations.is_some() =>
+        {
+            checker.error(
+                location,
+                "aggregations",
+                "scan queries don't support aggregations",
+            )
+        }
+        _ => {}
+    }
+
+    if let Some(filter) = &tspoller.filter {
+        check_druid_object(checker, location, "filter", filter);
+    }
+
+    // Names produced by the query, which are the only valid field_names
+    let mut produced = HashSet::new();
+    for (field, value) in [
+        ("aggregations", &tspoller.aggregations),
+        ("post_aggregations", &tspoller.post_aggregations),
+    ] {
+        let Some(value) = value else {
+            continue;
+        };
+        let Value::Array(items) = value else {
+            checker.error(location, field, "expected an array");
+            continue;
+        };
+        for item in items {
+            if check_druid_object(checker, location, field, item) {
+                match item.get("name").and_then(Value::as_str) {
+                    Some(name) => {
+                        produced.insert(name.to_string());
+                    }
+                    None => checker.error(location, field, format!("missing 'name' in {}", item)),
+                }
+            }
+        }
+    }
+
+    if tspoller.aggregations.is_some() {
+        let field_names = tspoller.field_names.iter().flat_map(|field| {
+            [
+                Some(&field.name),
+                field.count_field.as_ref(),
+                field.sum_field.as_ref(),
+            ]
+            .into_iter()
+            .flatten()
+        });
+        for field_name in field_names {
+            if !produced.contains(field_name) {
+                checker.error(
+                    location,
+                    "field_names",
+                    format!(
+                        "'{}' is not produced by any aggregation or post-aggregation",
+                        field_name
+                    ),
+                );
+            }
+        }
+    }
+
+    for dimension in tspoller
+        .otel_dimension_to_attribute_map
+        .iter()
+        .flatten()
+        .map(|(d, _)| d)
+    {
+        if !tspoller.dimensions.contains(dimension) {
+            checker.error(
+                location,
+                "otel_dimension_to_attribute_map",
+                format!("'{}' is not one of the query dimensions", dimension),
+            );
+        }
+    }
+}
+
+/// Checks the unit and the metric kind options of a tspoller field
+fn check_field(
+    checker: &mut Checker,
+    location: &str,
+    tspoller: &TSPollerConfig,
+    field: &FieldConfig,
+) {
+    let kind = tspoller.field_kind(field);
+    let error = |checker: &mut Checker, message: String| {
+        checker.error(
+            location,
+            "field_names",
+            format!("'{}': {}", field.name, message),
+        )
+    };
+    check_unit(
+        checker,
+        location,
+        &format!("field_names '{}'.unit", field.name),
+        &field.unit,
+    );
+    match kind {
+        "histogram" => {
+            let bounds = field.bounds.as_deref().unwrap_or_default();
+            if bounds.is_empty() || bounds.windows(2).any(|b| b[0] >= b[1]) {
+                error(
+                    checker,
+                    "histogram fields need increasing bounds".to_string(),
+                );
+            }
+        }
+        "summary" => {
+            let quantiles = field.quantiles.as_deref().unwrap_or_default();
+            if quantiles.is_empty() || quantiles.iter().any(|q| !(0.0..=1.0).contains(q)) {
+                error(
+                    checker,
+                    "summary fields need quantiles between 0 and 1".to_string(),
+                );
+            }
+            if field.count_field.is_none() || field.sum_field.is_none() {
+                error(
+                    checker,
+                    "summary fields need a count_field and a sum_field".to_string(),
+                );
+            }
+        }
+        "gauge" | "sum" => {
+            if let Err(err) =
+                MetricKind::scalar(Some(kind), field.temporality.as_deref(), field.monotonic)
+            {
+                error(checker, err.to_string());
+            }
+        }
+        other => error(
+            checker,
+            format!(
+                "invalid kind {}, expected gauge, sum, histogram or summary",
+                other
+            ),
+        ),
+    }
+}
+
+/// Units that aren't UCUM are still exported, but backends may not convert or
+/// display them, so they only produce a warning
+fn check_unit(checker: &mut Checker, location: &str, field: &str, unit: &Option<String>) {
+    if let Some(unit) = unit {
+        if !ucum::is_valid_unit(unit) {
+            checker.warn(
+                location,
+                field,
+                format!(
+                    "'{}' is not a UCUM unit such as By, ms, % or {{packets}}/s",
+                    unit
+                ),
+            );
+        }
+    }
+}
+
+/// A tspoller with a raw query template only uses the template for its query,
+/// so the fields that would otherwise build the query must not be set
+fn check_query_template(checker: &mut Checker, location: &str, tspoller: &TSPollerConfig) {
+    let field = match (&tspoller.query, &tspoller.query_file) {
+        (Some(_), Some(_)) => {
+            checker.error(
+                location,
+                "query_file",
+                "query and query_file are mutually exclusive",
+            );
+            return;
+        }
+        (Some(_), None) => "query",
+        _ => "query_file",
+    };
+
+    for (unused, is_set) in [
+        (
+            "query_type",
+            tspoller.query_type() != Some(TSQueryType::GroupBy),
+        ),
+        ("dimensions", !tspoller.dimensions.is_empty()),
+        ("granularity", tspoller.granularity.is_some()),
+        ("filter", tspoller.filter.is_some()),
+        ("aggregations", tspoller.aggregations.is_some()),
+        ("post_aggregations", tspoller.post_aggregations.is_some()),
+        ("metric", tspoller.metric.is_some()),
+        ("threshold", tspoller.threshold.is_some()),
+        ("limit", tspoller.limit.is_some()),
+    ] {
+        if is_set {
+            checker.error(location, unused, "not used with a query template");
+        }
+    }
+
+    let template = match timeseries_poller::query_template(tspoller) {
+        Ok(Some(template)) => template,
+        Ok(None) => return,
+        Err(err) => {
+            checker.error(location, field, format!("{:#}", err));
+            return;
+        }
+    };
+    let has_placeholders = template.contains("{{interval}}")
+        || (template.contains("{{start}}") && template.contains("{{end}}"));
+    if !has_placeholders {
+        checker.error(
+            location,
+            field,
+            "missing {{interval}} or {{start}}/{{end}} placeholders, every poll would query \
+             the same interval",
+        );
+    }
+
+    let window = QueryWindow::latest(
+        Utc::now(),
+        tspoller.lag(),
+        tspoller.window(),
+        tspoller.alignment(),
+    );
+    if let Err(err) = timeseries_poller::render_query(&template, window)
+        .and_then(|query| timeseries_poller::template_query_type(&query))
+    {
+        checker.error(location, field, format!("{:#}", err));
+    }
+}
+
+/// Checks that a Druid filter/aggregation/post-aggregation is an object with a
+/// `type`, returning whether it was.
+fn check_druid_object(checker: &mut Checker, location: &str, field: &str, value: &Value) -> bool {
+    match value {
+        Value::Object(o) if o.get("type").is_some_and(Value::is_string) => true,
+        Value::Object(_) => {
+            checker.error(location, field, format!("missing 'type' in {}", value));
+            false
+        }
+        _ => {
+            checker.error(
+                location,
+                field,
+                format!("expected an object, found {}", value),
+            );
+            false
+        }
+    }
+}
+
+/// Timeseries pollers only have the attributes from `otel_attributes`, their
+/// dimensions and their enrichers, so other `datapoint_attributes` are likely
+/// typos. Generic pollers aren't checked as aggregators add attributes too.
+fn check_datapoint_attributes(
+    checker: &mut Checker,
+    location: &str,
+    config: &GlobalConfig,
+    tspoller: &TSPollerConfig,
+    attributes: &BTreeMap<String, String>,
+) {
+    let tspoller_enrichers = tspoller.enrichers.as_deref().unwrap_or_default();
+    let enriched: HashSet<&str> = config
+        .enrichers
+        .iter()
+        .flatten()
+        .filter(|e| tspoller_enrichers.contains(&e.name))
+        .flat_map(|e| &e.result_mappings)
+        .map(|m| m.result_attribute.as_str())
+        .collect();
+    for name in tspoller.datapoint_attributes.iter().flatten() {
+        if !attributes.contains_key(name) && !enriched.contains(name.as_str()) {
+            checker.warn(
+                location,
+                "datapoint_attributes",
+                format!(
+                    "'{}' is not set by otel_attributes, otel_dimension_to_attribute_map or \
+                     the tspoller's enrichers",
+                    name
+                ),
+            );
+        }
+    }
+}
+
+fn check_references(
+    checker: &mut Checker,
+    location: &str,
+    enrichers: Option<&[String]>,
+    exporters: Option<&[String]>,
+    enricher_names: &HashSet<&str>,
+    exporter_names: &HashSet<&str>,
+) {
+    for name in enrichers.unwrap_or_default() {
+        if !enricher_names.contains(name.as_str()) {
+            checker.error(
+                location,
+                "enrichers",
+                format!("unknown enricher '{}'", name),
+            );
+        }
+    }
+    for name in exporters.unwrap_or_default() {
+        if !exporter_names.contains(name.as_str()) {
+            checker.error(
+                location,
+                "exporters",
+                format!("unknown exporter '{}'", name),
+            );
+        }
+    }
+}
+
+fn check_duplicate_metric(
+    checker: &mut Checker,
+    metric_keys: &mut HashMap<(String, BTreeMap<String, String>), String>,
+    location: &str,
+    key: (String, BTreeMap<String, String>),
+) {
+    let name = key.0.clone();
+    if let Some(other) = metric_keys.get(&key) {
+        checker.error(
+            location,
+            "name",
+            format!(
+                "metric '{}' with the same attributes is also produced by {}",
+                name, other
+            ),
+        );
+    } else {
+        metric_keys.insert(key, location.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY_FILE: &str = "../intersight_api/tests/examples/example-v2.pem";
+
+    /// Checks `body` with the API key settings and an OTLP exporter added,
+    /// returning the issues and the warnings found
+    fn check_toml(body: &str) -> (Vec<String>, Vec<String>) {
+        let toml = format!(
+            "key_id = \"a/b/c\"\nkey_file = \"{}\"\notel_collector_endpoint = \"http://localhost:4317\"\n{}",
+            KEY_FILE, body
+        );
+        let config = GlobalConfig::from_toml_str(&toml).expect("config should parse");
+        let checker = super::check(&config);
+        let strings = |issues: &[ConfigIssue]| issues.iter().map(|i| i.to_string()).collect();
+        (strings(&checker.issues), strings(&checker.warnings))
+    }
+
+    /// The issues found in `body`, see [`check_toml`]
+    fn check(body: &str) -> Vec<String> {
+        check_toml(body).0
+    }
+
+    #[test]
+    fn test_check_valid_config() {
+        let issues = check(
+            r#"
+            [[pollers]]
+            name = "intersight.alarms.count"
+            otel_attributes = { severity = "critical" }
+            api_query = "api/v1/cond/Alarms?$count=true"
+            aggregator = "result_count"
+
+            [[pollers]]
+            name = "intersight.alarms.count"
+            otel_attributes = { severity = "warning" }
+            api_query = "api/v1/cond/Alarms?$count=true"
+            aggregator = "result_count"
+            "#,
+        );
+        assert!(issues.is_empty(), "{:?}", issues);
+    }
+
+    #[test]
+    fn test_check_poller_errors() {
+        let issues = check(
+            r#"
+            [[pollers]]
+            name = "bad"
+            api_query = "api/v1/ntp/Policies"
+            api_method = "post"
+            api_body = "{not json"
+            aggregator = "nope"
+            enrichers = ["missing"]
+            exporters = ["default", "other"]
+            "#,
+        );
+        assert_eq!(
+            issues,
+            vec![
+                "pollers[0] 'bad': aggregator: Invalid aggregator nope",
+                "pollers[0] 'bad': api_body: invalid JSON: key must be a string at line 1 column 2",
+                "pollers[0] 'bad': enrichers: unknown enricher 'missing'",
+                "pollers[0] 'bad': exporters: unknown exporter 'other'",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_api_body_without_post_is_a_warning() {
+        let (issues, warnings) = check_toml(
+            r#"
+            [[pollers]]
+            name = "ntp"
+            api_query = "api/v1/ntp/Policies"
+            api_body = "{}"
+            aggregator = "count_results"
+            "#,
+        );
+        assert!(issues.is_empty(), "{:?}", issues);
+        assert_eq!(
+            warnings[0],
+            "pollers[0] 'ntp': api_body: api_body is only sent when api_method = \"post\""
+        );
+    }
+
+    #[test]
+    fn test_check_page_size_errors() {
+        let issues = check(
+            r#"
+            [[pollers]]
+            name = "servers"
+            api_query = "api/v1/compute/PhysicalSummaries?$top=10"
+            aggregator = "count_results"
+            page_size = 0
+
+            [[pollers]]
+            name = "vms"
+            api_query = "api/v1/virtualization/VirtualMachines"
+            aggregator = "count_results"
+            page_size = 5000
+
+            [[pollers]]
+            name = "search"
+            api_query = "api/v1/search/SearchItems"
+            api_method = "post"
+            aggregator = "count_results"
+            page_size = 100
+            "#,
+        );
+        assert_eq!(
+            issues,
+            vec![
+                "pollers[0] 'servers': page_size: page_size must be between 1 and 1000, the most objects Intersight returns per request",
+                "pollers[0] 'servers': page_size: api_query must not contain $top or $skip when page_size is set",
+                "pollers[1] 'vms': page_size: page_size must be between 1 and 1000, the most objects Intersight returns per request",
+                "pollers[2] 'search': page_size: page_size is only supported for GET queries",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_poller_metrics() {
+        let issues = check(
+            r#"
+            [[pollers]]
+            name = "servers"
+            api_query = "api/v1/compute/PhysicalSummaries"
+            aggregator = "count_results"
+            metrics = [
+                { name = "intersight.server.count", aggregator = "count_results" },
+                { name = "intersight.server.memory", aggregator = "stats" },
+                { name = "intersight.server.count", aggregator = "count_results" },
+            ]
+
+            [[pollers]]
+            name = "empty"
+            api_query = "api/v1/compute/PhysicalSummaries"
+            "#,
+        );
+        assert_eq!(
+            issues,
+            vec![
+                "pollers[0] 'servers': aggregator: aggregator must not be set when metrics are configured",
+                "pollers[0] 'servers': metrics[1].aggregator: invalid aggregator_options for aggregator stats",
+                "pollers[0] 'servers': name: metric 'intersight.server.count' with the same attributes is also produced by pollers[0] 'servers'",
+                "pollers[1] 'empty': aggregator: either aggregator or metrics is required",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_enricher_errors() {
+        let issues = check(
+            r#"
+            [[enrichers]]
+            name = "profile"
+            source_attribute = "host.id"
+            source_value_regex = "[unclosed"
+            query_template = "api/v1/server/Profiles"
+            result_mappings = [{ result_field = "Results[0", result_attribute = "x" }]
+            "#,
+        );
+        assert_eq!(issues.len(), 3, "{:?}", issues);
+        assert!(issues[0].starts_with("enrichers[0] 'profile': source_value_regex: invalid regex"));
+        assert!(issues[1].starts_with("enrichers[0] 'profile': query_template:"));
+        assert!(issues[2].starts_with("enrichers[0] 'profile': result_mappings: invalid JSONPath"));
+    }
+
+    #[test]
+    fn test_check_tspoller_errors() {
+        let issues = check(
+            r#"
+            [[tspollers]]
+            name = "cpu"
+            datasource = "PhysicalEntities"
+            dimensions = ["host.id"]
+            filter = "instrument.name"
+            granularity = 5
+            aggregations = [{ name = "cpu_util", fieldName = "hw.cpu.utilization_c0" }]
+            field_names = ["cpu_util", "memory"]
+            otel_dimension_to_attribute_map = { "host.name" = "host.name" }
+            "#,
+        );
+        assert_eq!(
+            issues,
+            vec![
+                "tspollers[0] 'cpu': granularity: expected a granularity name or an object with a 'type', got 5",
+                "tspollers[0] 'cpu': filter: expected an object, found \"instrument.name\"",
+                "tspollers[0] 'cpu': aggregations: missing 'type' in {\"fieldName\":\"hw.cpu.utilization_c0\",\"name\":\"cpu_util\"}",
+                "tspollers[0] 'cpu': field_names: 'cpu_util' is not produced by any aggregation or post-aggregation",
+                "tspollers[0] 'cpu': field_names: 'memory' is not produced by any aggregation or post-aggregation",
+                "tspollers[0] 'cpu': otel_dimension_to_attribute_map: 'host.name' is not one of the query dimensions",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_tspoller_query_types() {
+        let issues = check(
+            r#"
+            [[tspollers]]
+            name = "top"
+            datasource = "PhysicalEntities"
+            query_type = "topN"
+            dimensions = ["host.id", "host.name"]
+            field_names = ["top_power"]
+
+            [[tspollers]]
+            name = "total"
+            datasource = "PhysicalEntities"
+            query_type = "timeseries"
+            dimensions = ["host.id"]
+            field_names = ["total_power"]
+
+            [[tspollers]]
+            name = "raw"
+            datasource = "PhysicalEntities"
+            query_type = "select"
+            dimensions = []
+            field_names = ["raw_power"]
+            "#,
+        );
+        assert_eq!(
+            issues,
+            vec![
+                "tspollers[0] 'top': dimensions: topN queries need exactly one dimension",
+                "tspollers[0] 'top': metric: metric is required for topN queries",
+                "tspollers[0] 'top': threshold: threshold is required for topN queries",
+                "tspollers[1] 'total': dimensions: timeseries queries have no dimensions",
+                "tspollers[2] 'raw': query_type: expected one of groupBy, timeseries, topN or scan, got select",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_tspoller_query_templates() {
+        let issues = check(
+            r#"
+            [[tspollers]]
+            name = "raw"
+            datasource = "PhysicalEntities"
+            field_names = ["raw_power"]
+            query = '{"queryType": "groupBy", "intervals": ["{{interval}}"], "having": {}}'
+
+            [[tspollers]]
+            name = "fixed"
+            datasource = "PhysicalEntities"
+            field_names = ["fixed_power"]
+            granularity = "hour"
+            query = '{"queryType": "search", "intervals": ["2025-01-15/2025-01-16"]}'
+
+            [[tspollers]]
+            name = "missing"
+            datasource = "PhysicalEntities"
+            field_names = ["missing_power"]
+            query_file = "/nonexistent/query.json"
+            "#,
+        );
+        assert_eq!(issues.len(), 4, "{:?}", issues);
+        assert_eq!(
+            issues[0],
+            "tspollers[1] 'fixed': granularity: not used with a query template"
+        );
+        assert_eq!(
+            issues[1],
+            "tspollers[1] 'fixed': query: missing {{interval}} or {{start}}/{{end}} \
+             placeholders, every poll would query the same interval"
+        );
+        assert!(issues[2].starts_with("tspollers[1] 'fixed': query: unsupported queryType search"));
+        assert!(issues[3].starts_with(
+            "tspollers[2] 'missing': query_file: Unable to read query_file /nonexistent/query.json"
+        ));
+    }
+
+    #[test]
+    fn test_check_metric_kinds() {
+        let issues = check(
+            r#"
+            [[pollers]]
+            name = "intersight.alarms.new"
+            api_query = "api/v1/cond/Alarms?$count=true"
+            aggregator = "result_count"
+            kind = "histogram"
+
+            [[tspollers]]
+            name = "hx"
+            datasource = "hx"
+            dimensions = []
+            field_names = [
+                { name = "ops", kind = "sum", temporality = "sometimes" },
+                { name = "latency", kind = "histogram", bounds = [100.0, 10.0] },
+                { name = "latency_q", kind = "summary", quantiles = [0.5, 99.0],
+                  count_field = "latency_count", sum_field = "latency_sum" },
+                { name = "latency_p50", kind = "summary", quantiles = [0.5], sum_field = "latency_sum" },
+                { name = "size", kind = "counter" },
+            ]
+            "#,
+        );
+        assert_eq!(
+            issues,
+            vec![
+                "pollers[0] 'intersight.alarms.new': kind: Invalid kind histogram, expected gauge or sum",
+                "tspollers[0] 'hx': field_names: 'ops': Invalid temporality sometimes, expected delta or cumulative",
+                "tspollers[0] 'hx': field_names: 'latency': histogram fields need increasing bounds",
+                "tspollers[0] 'hx': field_names: 'latency_q': summary fields need quantiles between 0 and 1",
+                "tspollers[0] 'hx': field_names: 'latency_p50': summary fields need a count_field and a sum_field",
+                "tspollers[0] 'hx': field_names: 'size': invalid kind counter, expected gauge, sum, histogram or summary",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_duplicate_metrics() {
+        let issues = check(
+            r#"
+            [[pollers]]
+            name = "intersight.vm.count"
+            api_query = "api/v1/virtualization/VirtualMachines?$count=true"
+            aggregator = "result_count"
+
+            [[pollers]]
+            name = "intersight.vm.count"
+            api_query = "api/v1/virtualization/VirtualMachines?$count=true"
+            aggregator = "result_count"
+            "#,
+        );
+        assert_eq!(
+            issues,
+            vec!["pollers[1] 'intersight.vm.count': name: metric 'intersight.vm.count' with the same attributes is also produced by pollers[0] 'intersight.vm.count'"]
+        );
+    }
+
+    #[test]
+    fn test_check_duplicate_tspoller_metrics() {
+        let tspoller = |name: &str, datasource: &str, filter: &str| {
+            format!(
+                r#"
+                [[tspollers]]
+                name = "{}"
+                datasource = "{}"
+                dimensions = []
+                field_names = ["hw.host.power"]
+                aggregations = [{{ type = "longMax", name = "hw.host.power", fieldName = "hw.host.power" }}]
+                {}
+                "#,
+                name, datasource, filter
+            )
+        };
+        let rack_filter =
+            r#"filter = { type = "selector", dimension = "host.type", value = "rack" }"#;
+        let blade_filter =
+            r#"filter = { type = "selector", dimension = "host.type", value = "blade" }"#;
+
+        // A different datasource or filter queries different data
+        let issues = check(&format!(
+            "{}{}{}",
+            tspoller("a", "PhysicalEntities", rack_filter),
+            tspoller("b", "PhysicalEntities", blade_filter),
+            tspoller("c", "OtherEntities", rack_filter),
+        ));
+        assert!(issues.is_empty(), "{:?}", issues);
+
+        let issues = check(&format!(
+            "{}{}",
+            tspoller("a", "PhysicalEntities", rack_filter),
+            tspoller("b", "PhysicalEntities", rack_filter),
+        ));
+        assert_eq!(
+            issues,
+            vec!["tspollers[1] 'b': name: metric 'hw.host.power' with the same attributes is also produced by tspollers[0] 'a'"]
+        );
+    }
+
+    #[test]
+    fn test_check_exporter_errors() {
+        let issues = check(
+            r#"
+            [[exporters]]
+            name = "default"
+            type = "stdout"
+            format = "xml"
+
+            [[exporters]]
+            name = "archive"
+            type = "file"
+            "#,
+        );
+        assert_eq!(
+            issues,
+            vec![
+                "exporters[1] 'default': name: duplicate exporter name",
+                "exporters[1] 'default': format: Invalid stdout exporter format xml",
+                "exporters[2] 'archive': path: file exporter requires a path",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_check_unit_warnings() {
+        let (issues, warnings) = check_toml(
+            r#"
+            [[pollers]]
+            name = "intersight.alarms.count"
+            unit = "{alarms}"
+            description = "Number of alarms"
+            api_query = "api/v1/cond/Alarms?$count=true"
+            aggregator = "result_count"
+
+            [[pollers]]
+            name = "intersight.vm.memory"
+            unit = "bytes"
+            api_query = "api/v1/virtualization/VirtualMachines"
+            aggregator = "count_results"
+
+            [[pollers]]
+            name = "intersight.vm"
+            api_query = "api/v1/virtualization/VirtualMachines"
+            unit = "By"
+            metrics = [
+                { name = "intersight.vm.cpu", aggregator = "count_results", unit = "1" },
+                { name = "intersight.vm.disk", aggregator = "count_results", unit = "MB" },
+            ]
+
+            [[tspollers]]
+            name = "power"
+            datasource = "PhysicalEntities"
+            dimensions = ["host.id"]
+            field_names = [
+                { name = "hw.host.power", unit = "W" },
+                { name = "hw.host.energy", unit = "kWh" },
+            ]
+            aggregations = [
+                { type = "longMax", name = "hw.host.power", fieldName = "hw.host.power" },
+                { type = "longSum", name = "hw.host.energy", fieldName = "hw.host.energy" },
+            ]
+            "#,
+        );
+
+        // A poller level unit is the default for its metrics
+        assert!(issues.is_empty(), "{:?}", issues);
+
+        // Invalid units are only warnings
+        assert_eq!(warnings.len(), 3, "{:?}", warnings);
+        assert!(warnings[0]
+            .starts_with("pollers[1] 'intersight.vm.memory': unit: 'bytes' is not a UCUM unit"));
+        assert!(warnings[1]
+            .starts_with("pollers[2] 'intersight.vm': metrics[1].unit: 'MB' is not a UCUM unit"));
+        assert!(warnings[2].starts_with(
+            "tspollers[0] 'power': field_names 'hw.host.energy'.unit: 'kWh' is not a UCUM unit"
+        ));
+    }
+
+    #[test]
+    fn test_check_datapoint_attributes() {
+        let (issues, warnings) = check_toml(
+            r#"
+            [[enrichers]]
+            name = "profile"
+            source_attribute = "host.id"
+            query_template = "api/v1/server/Profiles?$filter=AssignedServer.Moid eq '{value}'"
+            result_mappings = [{ result_field = "$.Results[0].Name", result_attribute = "profile" }]
+
+            [[tspollers]]
+            name = "network"
+            datasource = "NetworkInterfaces"
+            dimensions = ["host.id", "hw.network.io.direction"]
+            field_names = ["hw.network.io"]
+            aggregations = [{ type = "longSum", name = "hw.network.io", fieldName = "hw.network.io" }]
+            otel_attributes = { source = "intersight" }
+            datapoint_attributes = ["direction", "source", "profile", "severity"]
+            enrichers = ["profile"]
+
+            [tspollers.otel_dimension_to_attribute_map]
+            "host.id" = "host.id"
+            "hw.network.io.direction" = "direction"
+            "#,
+        );
+        assert!(issues.is_empty(), "{:?}", issues);
+        assert_eq!(
+            warnings,
+            vec!["tspollers[0] 'network': datapoint_attributes: 'severity' is not set by otel_attributes, otel_dimension_to_attribute_map or the tspoller's enrichers"]
+        );
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt::Write;
+
+use anyhow::{bail, Result};
+use chrono::{DateTime, SecondsFormat, Utc};
+use opentelemetry_proto::tonic::collector::metrics::v1::ExportMetricsServiceRequest;
+use opentelemetry_proto::tonic::common::v1::{any_value, KeyValue};
+use opentelemetry_proto::tonic::metrics::v1::ResourceMetrics;
+
+use crate::intersight_poller::IntersightMetricBatch;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ConsoleFormat {
+    Table,
+    OtlpJson,
+}
+
+impl ConsoleFormat {
+    pub fn from_config(format: Option<&str>) -> Result<ConsoleFormat> {
+        match format.unwrap_or("table") {
+            "table" => Ok(ConsoleFormat::Table),
+            "otlp_json" => Ok(ConsoleFormat::OtlpJson),
+            other => bail!("Invalid stdout exporter format {}", other),
+        }
+    }
+}
+
+/// Prints every batch to stdout, either as a human readable table or as one
+/// OTLP-JSON `ExportMetricsServiceRequest` per line.
+pub struct ConsoleExporter {
+    format: ConsoleFormat,
+}
+
+impl ConsoleExporter {
+    pub fn new(format: ConsoleFormat) -> Self {
+        ConsoleExporter { format }
+    }
+
+    pub fn export(&self, batch: IntersightMetricBatch) {
+        let out = match self.format {
+            ConsoleFormat::Table => format_table(&batch),
+            ConsoleFormat::OtlpJson => match to_otlp_json(batch) {
+                Ok(json) => json + "\n",
+                Err(err) => {
+                    error!("Unable to serialise metrics to OTLP-JSON: {}", err);
+                    return;
+                }
+            },
+        };
+        print!("{}", out);
+    }
+}
+
+/// Serialises a batch as a single-line OTLP-JSON `ExportMetricsServiceRequest`.
+pub fn to_otlp_json(batch: IntersightMetricBatch) -> serde_json::Result<String> {
+    let request = ExportMetricsServiceRequest {
+        resource_metrics: batch.into_iter().map(ResourceMetrics::from).collect(),
+    };
+    serde_json::to_string(&request)
+}
+
+fn format_table(batch: &IntersightMetricBatch) -> String {
+    let mut rows = vec![[
+        "TIME".to_string(),
+        "METRIC".to_string(),
+        "VALUE".to_string(),
+        "ATTRIBUTES".to_string(),
+    ]];
+    for rm in batch {
+        let resource_attributes: Vec<String> = rm.attributes.iter().map(format_kv).collect();
+        for m in &rm.metrics {
+            let mut attributes = resource_attributes.clone();
+            attributes.extend(m.attributes.iter().map(|(k, v)| format!("{}={}", k, v)));
+            attributes.extend(m.datapoint_attributes.iter().map(format_kv));
+            rows.push([
+                DateTime::<Utc>::from(m.time).to_rfc3339_opts(SecondsFormat::Secs, true),
+                m.name.clone(),
+                m.value.to_string(),
+                attributes.join(" "),
+            ]);
+        }
+    }
+    if rows.len() == 1 {
+        return String::new();
+    }
+
+    let mut widths = [0; 3];
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let mut out = String::new();
+    for row in &rows {
+        let _ = writeln!(
+            out,
+            "{:<w0$}  {:<w1$}  {:>w2$}  {}",
+            row[0],
+            row[1],
+            row[2],
+            row[3],
+            w0 = widths[0],
+            w1 = widths[1],
+            w2 = widths[2],
+        );
+    }
+    out
+}
+
+fn format_kv(kv: &KeyValue) -> String {
+    let value = match kv.value.as_ref().and_then(|v| v.value.as_ref()) {
+        Some(any_value::Value::StringValue(s)) => s.clone(),
+        Some(any_value::Value::BoolValue(b)) => b.to_string(),
+        Some(any_value::Value::IntValue(i)) => i.to_string(),
+        Some(any_value::Value::DoubleValue(d)) => d.to_string(),
+        Some(other) => format!("{:?}", other),
+        None => String::new(),
+    };
+    format!("{}={}", kv.key, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intersight_poller::{IntersightMetric, IntersightResourceMetrics};
+    use opentelemetry_proto::tonic::common::v1::AnyValue;
+    use std::time::{Duration, SystemTime};
+
+    fn make_batch() -> IntersightMetricBatch {
+        let time = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        vec![IntersightResourceMetrics {
+            attributes: vec![KeyValue {
+                key: "host.id".to_string(),
+                value: Some(AnyValue {
+                    value: Some(any_value::Value::StringValue("abc".to_string())),
+                }),
+            }],
+            metrics: vec![
+                IntersightMetric::new("intersight.vm.count", 12.0, None, time, time),
+                IntersightMetric::new("intersight.a", 3.5, None, time, time),
+            ],
+            start_time: None,
+        }]
+    }
+
+    #[test]
+    fn test_format_table() {
+        assert_eq!(
+            format_table(&make_batch()),
+            "TIME                  METRIC               VALUE  ATTRIBUTES\n\
+             2023-11-14T22:13:20Z  intersight.vm.count     12  host.id=abc\n\
+             2023-11-14T22:13:20Z  intersight.a           3.5  host.id=abc\n"
+        );
+    }
+
+    #[test]
+    fn test_format_table_empty_batch() {
+        assert_eq!(format_table(&vec![]), "");
+    }
+
+    #[test]
+    fn test_to_otlp_json() {
+        let json: serde_json::Value =
+            serde_json::from_str(&to_otlp_json(make_batch()).unwrap()).unwrap();
+        let metric = &json["resourceMetrics"][0]["scopeMetrics"][0]["metrics"][0];
+        assert_eq!(metric["name"], "intersight.vm.count");
+        assert_eq!(
+            metric["gauge"]["dataPoints"][0]["timeUnixNano"],
+            "1700000000000000000"
+        );
+    }
+
+    #[test]
+    fn test_console_format_from_config() {
+        assert_eq!(
+            ConsoleFormat::from_config(None).unwrap(),
+            ConsoleFormat::Table
+        );
+        assert_eq!(
+            ConsoleFormat::from_config(Some("otlp_json")).unwrap(),
+            ConsoleFormat::OtlpJson
+        );
+        assert!(ConsoleFormat::from_config(Some("xml")).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+use anyhow::{anyhow, bail, Result};
+use chrono::{DateTime, Duration, Utc};
+use serde_json::{json, Value};
+
+use crate::coalescing_client::CoalescingClient;
+use crate::config::DiscoverArgs;
+use crate::intersight_poller::timeseries_poller;
+
+/// Datasources probed when none are given on the command line. Intersight has
+/// no API listing its telemetry datasources, so these are the ones known to
+/// exist; others can be probed with `--datasource`. The names are Intersight's
+/// and case-sensitive: the HyperFlex datasource really is the lowercase `hx`.
+const WELL_KNOWN_DATASOURCES: &[&str] = &["PhysicalEntities", "NetworkInterfaces", "hx"];
+
+/// A column of a Druid datasource as reported by SegmentMetadata
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    pub name: String,
+    pub column_type: String,
+}
+
+impl Column {
+    /// String columns are dimensions, the others are metric columns
+    pub fn is_dimension(&self) -> bool {
+        self.column_type == "STRING"
+    }
+
+    /// The aggregation the tspoller skeleton uses for this column, `None` for
+    /// dimensions and complex columns such as sketches
+    fn aggregation_type(&self) -> Option<&'static str> {
+        match self.column_type.as_str() {
+            "LONG" => Some("longMax"),
+            "FLOAT" | "DOUBLE" => Some("doubleMax"),
+            _ => None,
+        }
+    }
+}
+
+/// Runs the `discover` subcommand: lists the available telemetry datasources
+/// with their dimensions and metric columns, or prints a skeleton
+/// `[[tspollers]]` block for each datasource with `--toml`.
+pub async fn run(args: &DiscoverArgs, client: &CoalescingClient) -> Result<()> {
+    let datasources: Vec<String> = if args.datasources.is_empty() {
+        WELL_KNOWN_DATASOURCES
+            .iter()
+            .map(|d| d.to_string())
+            .collect()
+    } else {
+        args.datasources.clone()
+    };
+
+    let mut found = 0;
+    for datasource in &datasources {
+        let max_time = match timeseries_poller::latest_data_time(client, datasource).await {
+            Ok(max_time) => max_time,
+            Err(err) => {
+                warn!("Datasource {} is not available: {:#}", datasource, err);
+                continue;
+            }
+        };
+        let columns = match segment_metadata(client, datasource, max_time).await {
+            Ok(columns) => columns,
+            Err(err) => {
+                warn!(
+                    "Unable to get the columns of datasource {}: {:#}",
+                    datasource, err
+                );
+                continue;
+            }
+        };
+        found += 1;
+
+        if args.toml {
+            println!("{}", tspoller_skeleton(datasource, &columns));
+            continue;
+        }
+
+        println!("{} (latest data: {})", datasource, max_time.to_rfc3339());
+        for (heading, dimensions) in [("Dimensions", true), ("Metric columns", false)] {
+            println!("  {}:", heading);
+            for column in columns.iter().filter(|c| c.is_dimension() == dimensions) {
+                println!("    {} ({})", column.name, column.column_type);
+            }
+        }
+        println!();
+    }
+
+    if found == 0 {
+        bail!(
+            "None of the datasources {} are available",
+            datasources.join(", ")
+        );
+    }
+    Ok(())
+}
+
+/// Queries Druid's SegmentMetadata for the columns of a datasource during the
+/// last day before its latest data
+async fn segment_metadata(
+    client: &CoalescingClient,
+    datasource: &str,
+    max_time: DateTime<Utc>,
+) -> Result<Vec<Column>> {
+    let interval = format!(
+        "{}/{}",
+        (max_time - Duration::days(1)).to_rfc3339(),
+        (max_time + Duration::seconds(1)).to_rfc3339()
+    );
+    let body = json!(
+        {
+            "queryType": "segmentMetadata",
+            "dataSource": datasource,
+            "intervals": [ interval ],
+            "merge": true,
+            "analysisTypes": [],
+        }
+    );
+    let response = client
+        .post("api/v1/telemetry/SegmentMetadata", body)
+        .await?;
+    parse_columns(&response)
+        .ok_or_else(|| anyhow!("unexpected SegmentMetadata response: {}", response))
+}
+
+/// The columns of a merged SegmentMetadata response, sorted by name and
+/// without the `__time` column
+fn parse_columns(response: &Value) -> Option<Vec<Column>> {
+    let columns = match response {
+        Value::Array(results) => results.first()?["columns"].as_object()?,
+        other => other["columns"].as_object()?,
+    };
+    let mut columns: Vec<Column> = columns
+        .iter()
+        .filter(|(name, _)| name.as_str() != "__time")
+        .map(|(name, column)| Column {
+            name: name.clone(),
+            column_type: column["type"].as_str().unwrap_or("UNKNOWN").to_string(),
+        })
+        .collect();
+    columns.sort_by(|a, b| a.name.cmp(&b.name));
+    Some(columns)
+}
+
+/// A `[[tspollers]]` block querying the maximum of every numeric column of a
+/// datasource, grouped by all of its dimensions, to be trimmed down by hand
+fn tspoller_skeleton(datasource: &str, columns: &[Column]) -> String {
+    let quote = |s: &str| Value::from(s).to_string();
+    let dimensions: Vec<&Column> = columns.iter().filter(|c| c.is_dimension()).collect();
+    let metrics: Vec<(&Column, &str)> = columns
+        .iter()
+        .filter_map(|c| c.aggregation_type().map(|t| (c, t)))
+        .collect();
+
+    let mut toml = String::new();
+    toml.push_str("[[tspollers]]\n");
+    toml.push_str(&format!("name = {}\n", quote(&datasource.to_lowercase())));
+    toml.push_str(&format!("datasource = {}\n", quote(datasource)));
+    toml.push_str("# Keep only the dimensions to group by\n");
+    toml.push_str("dimensions = [\n");
+    for column in &dimensions {
+        toml.push_str(&format!("    {},\n", quote(&column.name)));
+    }
+    toml.push_str("]\n");
+    toml.push_str("# Keep only the metrics to export\n");
+    toml.push_str("field_names = [\n");
+    for (column, _) in &metrics {
+        toml.push_str(&format!("    {},\n", quote(&column.name)));
+    }
+    toml.push_str("]\n");
+    toml.push_str("aggregations = [\n");
+    for (column, aggregation_type) in &metrics {
+        toml.push_str(&format!(
+            "    {{ type = {}, name = {}, fieldName = {} }},\n",
+            quote(aggregation_type),
+            quote(&column.name),
+            quote(&column.name)
+        ));
+    }
+    toml.push_str("]\n");
+    toml.push_str("[tspollers.otel_dimension_to_attribute_map]\n");
+    for column in &dimensions {
+        toml.push_str(&format!(
+            "{} = {}\n",
+            quote(&column.name),
+            quote(&column.name)
+        ));
+    }
+    toml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::GlobalConfig;
+    use crate::config_check;
+
+    fn columns() -> Vec<Column> {
+        parse_columns(&json!([{
+            "id": "merged",
+            "columns": {
+                "__time": {"type": "LONG"},
+                "host.id": {"type": "STRING"},
+                "instrument.name": {"type": "STRING"},
+                "hw.cpu.utilization_c0": {"type": "DOUBLE"},
+                "hw.errors": {"type": "LONG"},
+                "unique_hosts": {"type": "hyperUnique"}
+            }
+        }]))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_parse_columns() {
+        let columns = columns();
+        let names: Vec<&str> = columns.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "host.id",
+                "hw.cpu.utilization_c0",
+                "hw.errors",
+                "instrument.name",
+                "unique_hosts"
+            ]
+        );
+        assert!(columns[0].is_dimension());
+        assert!(!columns[1].is_dimension());
+        assert_eq!(parse_columns(&json!({"error": "nope"})), None);
+    }
+
+    #[test]
+    fn test_tspoller_skeleton_is_valid_config() {
+        let toml = tspoller_skeleton("PhysicalEntities", &columns());
+        assert!(toml.contains("fieldName = \"hw.cpu.utilization_c0\""));
+        assert!(!toml.contains("unique_hosts"));
+
+        let config = GlobalConfig::from_toml_str(&format!(
+            "key_id = \"a/b/c\"\nkey_file = \"key.pem\"\n{}",
+            toml
+        ))
+        .unwrap();
+        let tspoller = &config.tspollers.as_ref().unwrap()[0];
+        assert_eq!(tspoller.name, "physicalentities");
+        assert_eq!(tspoller.dimensions, ["host.id", "instrument.name"]);
+        let field_names: Vec<&str> = tspoller
+            .field_names
+            .iter()
+            .map(|f| f.name.as_str())
+            .collect();
+        assert_eq!(field_names, ["hw.cpu.utilization_c0", "hw.errors"]);
+
+        // Only the missing key file and exporter are reported
+        let issues = config_check::check_config(&config);
+        assert!(
+            issues.iter().all(|i| i.location == "global"),
+            "{:?}",
+            issues
+        );
+    }
+}
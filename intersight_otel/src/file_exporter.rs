@@ -0,0 +1,149 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::console_exporter::to_otlp_json;
+use crate::intersight_poller::IntersightMetricBatch;
+
+/// Appends every batch to a file as one OTLP-JSON `ExportMetricsServiceRequest`
+/// per line. When the file would grow beyond `max_size` bytes it is rotated to
+/// `<path>.1` (and older files to `<path>.2` etc.), keeping at most `max_files`
+/// rotated files.
+pub struct FileExporter {
+    path: PathBuf,
+    max_size: u64,
+    max_files: usize,
+    file: File,
+    size: u64,
+}
+
+impl FileExporter {
+    pub fn new(path: &str, max_size: u64, max_files: usize) -> Result<Self> {
+        let path = PathBuf::from(path);
+        let file = open_append(&path)?;
+        let size = file.metadata()?.len();
+        Ok(FileExporter {
+            path,
+            max_size,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    pub fn export(&mut self, batch: IntersightMetricBatch) -> Result<()> {
+        let mut line = to_otlp_json(batch)?;
+        line.push('\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            self.rotate()?;
+        }
+
+        self.file.write_all(line.as_bytes())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        if self.max_files == 0 {
+            self.file.set_len(0)?;
+            self.size = 0;
+            return Ok(());
+        }
+
+        let _ = fs::remove_file(rotated_path(&self.path, self.max_files));
+        for i in (1..self.max_files).rev() {
+            let from = rotated_path(&self.path, i);
+            if from.exists() {
+                fs::rename(&from, rotated_path(&self.path, i + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated_path(&self.path, 1))?;
+
+        self.file = open_append(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn open_append(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Unable to open {}", path.display()))
+}
+
+fn rotated_path(path: &Path, n: usize) -> PathBuf {
+    let mut p = path.as_os_str().to_owned();
+    p.push(format!(".{}", n));
+    PathBuf::from(p)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intersight_poller::{IntersightMetric, IntersightResourceMetrics};
+    use std::time::SystemTime;
+
+    fn make_batch() -> IntersightMetricBatch {
+        vec![IntersightResourceMetrics {
+            metrics: vec![IntersightMetric::new(
+                "m",
+                1.0,
+                None,
+                SystemTime::now(),
+                SystemTime::now(),
+            )],
+            ..Default::default()
+        }]
+    }
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("intersight_otel_{}_{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_export_writes_json_lines() {
+        let dir = test_dir("jsonl");
+        let path = dir.join("metrics.jsonl");
+        let mut exporter = FileExporter::new(path.to_str().unwrap(), 1 << 20, 3).unwrap();
+
+        exporter.export(make_batch()).unwrap();
+        exporter.export(make_batch()).unwrap();
+
+        let contents = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = contents.lines().collect();
+        assert_eq!(lines.len(), 2);
+        for line in lines {
+            let v: serde_json::Value = serde_json::from_str(line).unwrap();
+            assert!(v["resourceMetrics"].is_array());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_export_rotates_files() {
+        let dir = test_dir("rotate");
+        let path = dir.join("metrics.jsonl");
+        // Small enough that every batch triggers a rotation
+        let mut exporter = FileExporter::new(path.to_str().unwrap(), 10, 2).unwrap();
+
+        for _ in 0..4 {
+            exporter.export(make_batch()).unwrap();
+        }
+
+        assert!(path.exists());
+        assert!(rotated_path(&path, 1).exists());
+        assert!(rotated_path(&path, 2).exists());
+        assert!(!rotated_path(&path, 3).exists());
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
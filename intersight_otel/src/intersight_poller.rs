@@ -1,31 +1,98 @@
-use std::collections::BTreeMap;
-use std::sync::Arc;
+use std::collections::{hash_map::Entry, BTreeMap, HashMap};
+use std::sync::{Arc, LazyLock, RwLock};
 use std::time::SystemTime;
 
-use anyhow::{bail, Result};
-use generic_poller::Aggregator;
-use intersight_api::Client;
+use crate::coalescing_client::CoalescingClient;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
 use opentelemetry_proto::tonic::common::v1::{any_value, AnyValue, KeyValue};
-use tokio::{sync::mpsc::Sender, task::JoinHandle, time};
+use tokio::{task::JoinHandle, time};
 
 const ENRICH_TIMEOUT_SECS: u64 = 60;
+const MAX_CATCH_UP_WINDOWS: usize = 24;
+/// Times a tspoller queries a window before skipping it
+const MAX_WINDOW_ATTEMPTS: u32 = 3;
+/// Intersight returns at most this many objects per request, whatever `$top` is
+const MAX_PAGE_SIZE: u64 = 1000;
 
 use crate::attribute_enricher::AttributeEnricher;
-use crate::config::{OTelAttributeProvider, PollerConfig, TSPollerConfig};
+use crate::config::{OTelAttributeProvider, PollerConfig, PollerMetricConfig, TSPollerConfig};
+use crate::metric_merger::MetricRouter;
 
 mod generic_poller;
-mod timeseries_poller;
+pub(crate) mod timeseries_poller;
+
+pub use generic_poller::{parse_options, Aggregator};
+pub use timeseries_poller::QueryWindow;
 
 #[derive(Debug, Clone)]
 pub struct IntersightMetric {
     pub name: String,
     pub attributes: BTreeMap<String, String>,
+    /// Resource attributes moved to the data point by the poller's
+    /// `datapoint_attributes`, exported along with `attributes`
+    pub datapoint_attributes: Vec<KeyValue>,
+    /// The data point value; the number of observations for histograms and
+    /// summaries
     pub value: f64,
-    pub timestamp_offset: u64,
+    pub unit: String,
+    pub description: String,
+    pub kind: MetricKind,
     pub start_time: SystemTime,
     pub time: SystemTime,
 }
 
+/// How a metric is exported
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum MetricKind {
+    #[default]
+    Gauge,
+    /// A sum over `start_time` to `time` (delta) or since `start_time`
+    /// (cumulative)
+    Sum { cumulative: bool, monotonic: bool },
+    /// An explicit bucket histogram; `bucket_counts` has one more entry than
+    /// `bounds`, for values above the last bound
+    Histogram {
+        bounds: Vec<f64>,
+        bucket_counts: Vec<u64>,
+        sum: Option<f64>,
+    },
+    /// Values at the given quantiles, as `(quantile, value)` pairs
+    Summary {
+        quantiles: Vec<(f64, f64)>,
+        sum: f64,
+    },
+}
+
+impl MetricKind {
+    /// The kind of a gauge or sum metric from the `kind`, `temporality` and
+    /// `monotonic` options of a poller or tspoller field
+    pub fn scalar(
+        kind: Option<&str>,
+        temporality: Option<&str>,
+        monotonic: Option<bool>,
+    ) -> Result<MetricKind> {
+        match kind.unwrap_or("gauge") {
+            "gauge" => Ok(MetricKind::Gauge),
+            "sum" => {
+                let cumulative = match temporality.unwrap_or("delta") {
+                    "delta" => false,
+                    "cumulative" => true,
+                    other => bail!(
+                        "Invalid temporality {}, expected delta or cumulative",
+                        other
+                    ),
+                };
+                Ok(MetricKind::Sum {
+                    cumulative,
+                    monotonic: monotonic.unwrap_or(true),
+                })
+            }
+            other => bail!("Invalid kind {}, expected gauge or sum", other),
+        }
+    }
+}
+
 impl IntersightMetric {
     pub fn new(
         name: &str,
@@ -37,15 +104,18 @@ impl IntersightMetric {
         IntersightMetric {
             name: name.to_string(),
             attributes: attributes.unwrap_or_default(),
+            datapoint_attributes: vec![],
             value,
-            timestamp_offset: 0,
+            unit: String::new(),
+            description: String::new(),
+            kind: MetricKind::Gauge,
             start_time,
             time,
         }
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct IntersightResourceMetrics {
     pub attributes: Vec<KeyValue>,
     pub metrics: Vec<IntersightMetric>,
@@ -54,31 +124,141 @@ pub struct IntersightResourceMetrics {
 
 pub type IntersightMetricBatch = Vec<IntersightResourceMetrics>;
 
-fn get_aggregator_for_config(config: &PollerConfig) -> Result<Box<dyn Aggregator + Sync + Send>> {
-    match config.aggregator.as_str() {
-        "result_count" => Ok(Box::new(generic_poller::ResultCountAggregator::new(
-            config.name.clone(),
-        ))),
-        "count_results" => Ok(Box::new(generic_poller::ResultCountingAggregator::new(
-            config.name.clone(),
-        ))),
-        _ => bail!(format!("Invalid aggregator {}", config.aggregator)),
+/// Creates an aggregator for a poller from its configuration, including
+/// `aggregator_options` (see [`parse_options`]).
+pub type AggregatorFactory =
+    Arc<dyn Fn(&PollerConfig) -> Result<Box<dyn Aggregator + Sync + Send>> + Sync + Send>;
+
+static AGGREGATORS: LazyLock<RwLock<HashMap<String, AggregatorFactory>>> =
+    LazyLock::new(|| RwLock::new(builtin_aggregators()));
+
+/// Constructs a built-in aggregator for a poller
+type BuiltinFactory = fn(&PollerConfig) -> Result<Box<dyn Aggregator + Sync + Send>>;
+
+fn builtin_aggregators() -> HashMap<String, AggregatorFactory> {
+    use generic_poller::{
+        FieldValueAggregator, GroupByAggregator, InfoAggregator, JqAggregator,
+        ResultCountAggregator, ResultCountingAggregator, StateAggregator, StatsAggregator,
+        TimestampAgeAggregator,
+    };
+
+    let builtins: [(&str, BuiltinFactory); 9] = [
+        ("result_count", |c| named(c, ResultCountAggregator::new)),
+        ("count_results", |c| named(c, ResultCountingAggregator::new)),
+        ("group_by", |c| with_options(c, GroupByAggregator::new)),
+        ("field_value", |c| {
+            with_options(c, FieldValueAggregator::new)
+        }),
+        ("stats", |c| with_options(c, StatsAggregator::new)),
+        ("state", |c| with_options(c, StateAggregator::new)),
+        ("timestamp_age", |c| {
+            with_options(c, TimestampAgeAggregator::new)
+        }),
+        ("info", |c| with_options(c, InfoAggregator::new)),
+        ("jq", |c| with_options(c, JqAggregator::new)),
+    ];
+    builtins
+        .into_iter()
+        .map(|(name, factory)| (name.to_string(), Arc::new(factory) as AggregatorFactory))
+        .collect()
+}
+
+/// Builds an aggregator that only needs the poller name
+fn named<A>(
+    config: &PollerConfig,
+    new: fn(String) -> A,
+) -> Result<Box<dyn Aggregator + Sync + Send>>
+where
+    A: Aggregator + Sync + Send + 'static,
+{
+    Ok(Box::new(new(config.name.clone())))
+}
+
+/// Builds an aggregator from the poller name and its `aggregator_options`
+fn with_options<O, A>(
+    config: &PollerConfig,
+    new: fn(String, O) -> Result<A>,
+) -> Result<Box<dyn Aggregator + Sync + Send>>
+where
+    O: serde::de::DeserializeOwned,
+    A: Aggregator + Sync + Send + 'static,
+{
+    Ok(Box::new(new(config.name.clone(), parse_options(config)?)?))
+}
+
+/// Registers an aggregator that pollers can select with `aggregator = "<name>"`.
+/// Registering an existing name replaces the previous aggregator, including the
+/// built-in ones. Aggregators must be registered before the pollers are started.
+pub fn register_aggregator<F>(name: &str, factory: F)
+where
+    F: Fn(&PollerConfig) -> Result<Box<dyn Aggregator + Sync + Send>> + Sync + Send + 'static,
+{
+    AGGREGATORS
+        .write()
+        .unwrap()
+        .insert(name.to_string(), Arc::new(factory));
+}
+
+pub fn get_aggregator_for_config(
+    config: &PollerConfig,
+) -> Result<Box<dyn Aggregator + Sync + Send>> {
+    if let Some(metrics) = &config.metrics {
+        let mut aggregators = vec![];
+        for (metric, metric_config) in metrics.iter().zip(config.metric_configs()) {
+            let aggregator = get_aggregator_for_config(&metric_config)
+                .with_context(|| format!("metric {}", metric.name))?;
+            let kind = poller_metric_kind(&metric_config)
+                .with_context(|| format!("metric {}", metric.name))?;
+            // Use the poller's unit and description unless the metric has its own
+            let metric = PollerMetricConfig {
+                unit: metric_config.unit.clone(),
+                description: metric_config.description.clone(),
+                ..metric.clone()
+            };
+            aggregators.push(generic_poller::MetricAggregator::new(
+                aggregator,
+                &metric,
+                kind,
+                config.interval(),
+            ));
+        }
+        return Ok(Box::new(generic_poller::MultiAggregator::new(aggregators)));
+    }
+
+    let factory = AGGREGATORS.read().unwrap().get(&config.aggregator).cloned();
+    match factory {
+        Some(factory) => factory(config),
+        None => bail!(format!("Invalid aggregator {}", config.aggregator)),
+    }
+}
+
+/// Pagination stops at the first page with fewer than `page_size` objects, so
+/// a larger page size than Intersight returns would cut off the results. POST
+/// queries are sent as-is, so `page_size` would be silently ignored for them.
+pub fn check_page_size(config: &PollerConfig) -> Result<()> {
+    match config.page_size {
+        Some(page_size) if page_size == 0 || page_size > MAX_PAGE_SIZE => bail!(
+            "page_size must be between 1 and {}, the most objects Intersight returns per request",
+            MAX_PAGE_SIZE
+        ),
+        Some(_) if config.api_method.as_deref() == Some("post") => {
+            bail!("page_size is only supported for GET queries")
+        }
+        _ => Ok(()),
     }
 }
 
 pub fn start_intersight_poller(
-    tx: Sender<IntersightMetricBatch>,
-    client: &Client,
+    tx: MetricRouter,
+    client: &CoalescingClient,
     config: &PollerConfig,
     enrichers: Vec<Arc<AttributeEnricher>>,
 ) -> Result<JoinHandle<()>> {
     let client = (*client).clone();
     let config = (*config).clone();
     let interval = config.interval();
-    let query = config.api_query.clone();
-    let method = config.api_method.clone();
-    let body = config.api_body.clone();
 
+    check_page_size(&config).with_context(|| format!("poller {}", config.name))?;
     let aggregator = get_aggregator_for_config(&config)?;
 
     let handle = tokio::spawn(async move {
@@ -88,44 +268,18 @@ pub fn start_intersight_poller(
             let start_time = SystemTime::now();
             interval.tick().await;
 
-            let poll_result =
-                generic_poller::poll(&client, &query, &method, &body, aggregator.as_ref()).await;
-
-            if let Ok(mut r) = poll_result {
-                add_otel_attributes(&mut r, &config);
-                if !enrichers.is_empty() {
-                    let enrich_result = time::timeout(
-                        time::Duration::from_secs(ENRICH_TIMEOUT_SECS),
-                        async {
-                            for enricher in &enrichers {
-                                enricher.enrich_batch(&mut r).await;
-                            }
-                        },
-                    )
-                    .await;
-                    if enrich_result.is_err() {
-                        warn!(
-                            "Poller '{}': enrichment timed out after {}s, sending batch un-enriched",
-                            config.name, ENRICH_TIMEOUT_SECS
-                        );
-                    }
-                }
-                let metric_count: usize = r.iter().map(|rm| rm.metrics.len()).sum();
-                let resource_count = r.len();
-                if resource_count == 0 {
-                    warn!("Poller '{}': poll returned empty batch this tick", config.name);
-                } else {
-                    debug!(
-                        "Poller '{}': sending {} resources, {} metrics",
-                        config.name, resource_count, metric_count
-                    );
-                }
-                add_start_time(&mut r, start_time);
-                if let Err(err) = tx.send(r).await {
-                    error!("metrics receiver thread dropped: {}", err);
-                }
-            } else if let Err(err) = poll_result {
-                error!("error while polling Intersight: {}", err);
+            let poll_result = run_poller(
+                &client,
+                &config,
+                aggregator.as_ref(),
+                &enrichers,
+                start_time,
+            )
+            .await;
+
+            match poll_result {
+                Ok(r) => tx.send(r),
+                Err(err) => error!("error while polling Intersight: {}", err),
             }
         }
     });
@@ -134,8 +288,8 @@ pub fn start_intersight_poller(
 }
 
 pub fn start_intersight_tspoller(
-    tx: Sender<IntersightMetricBatch>,
-    client: &Client,
+    tx: MetricRouter,
+    client: &CoalescingClient,
     config: &TSPollerConfig,
     enrichers: Vec<Arc<AttributeEnricher>>,
 ) -> Result<JoinHandle<()>> {
@@ -143,59 +297,273 @@ pub fn start_intersight_tspoller(
     let config = (*config).clone();
 
     let handle = tokio::spawn(async move {
-        let mut interval = time::interval(time::Duration::from_secs(config.interval()));
+        // The window most recently exported by this poller. Each window is
+        // exported once, as soon as it is complete, and missed windows are
+        // caught up on in order.
+        let mut last_exported: Option<QueryWindow> = None;
+        // The window that failed last and how many times in a row
+        let mut failing: Option<(QueryWindow, u32)> = None;
 
         loop {
-            let start_time = SystemTime::now();
-            interval.tick().await;
+            let (latest, freshness) = latest_window(&client, &config, Utc::now()).await;
+            if let Some(freshness) = freshness {
+                tx.send(vec![freshness]);
+            }
+            let mut pending = QueryWindow::pending(last_exported, latest, config.alignment());
+            if pending.len() > MAX_CATCH_UP_WINDOWS {
+                warn!(
+                    "TSPoller '{}': {} windows behind, skipping all but the latest {}",
+                    config.name,
+                    pending.len(),
+                    MAX_CATCH_UP_WINDOWS
+                );
+                pending.drain(..pending.len() - MAX_CATCH_UP_WINDOWS);
+            }
 
-            let poll_result = timeseries_poller::poll(&client, &config).await;
-
-            if let Ok(mut r) = poll_result {
-                add_otel_attributes(&mut r, &config);
-                if !enrichers.is_empty() {
-                    let enrich_result = time::timeout(
-                        time::Duration::from_secs(ENRICH_TIMEOUT_SECS),
-                        async {
-                            for enricher in &enrichers {
-                                enricher.enrich_batch(&mut r).await;
-                            }
-                        },
-                    )
-                    .await;
-                    if enrich_result.is_err() {
-                        warn!(
-                            "TSPoller '{}': enrichment timed out after {}s, sending batch un-enriched",
-                            config.name, ENRICH_TIMEOUT_SECS
+            let mut failed = false;
+            for window in pending {
+                let start_time = SystemTime::now();
+                match run_tspoller(&client, &config, &enrichers, window, start_time).await {
+                    Ok(r) => {
+                        tx.send(r);
+                        last_exported = Some(window);
+                        failing = None;
+                    }
+                    Err(err) => {
+                        let attempts = failed_attempts(failing, window);
+                        if attempts >= MAX_WINDOW_ATTEMPTS {
+                            // Don't let a window that keeps failing, e.g. on a
+                            // Druid 400, hold up the windows after it
+                            warn!(
+                                "TSPoller '{}': skipping window {} after {} failed attempts: {}",
+                                config.name,
+                                window.interval(),
+                                attempts,
+                                err
+                            );
+                            last_exported = Some(window);
+                            failing = None;
+                            continue;
+                        }
+                        error!(
+                            "error while polling Intersight for window {}: {}",
+                            window.interval(),
+                            err
                         );
+                        failing = Some((window, attempts));
+                        failed = true;
+                        break;
                     }
                 }
-                let metric_count: usize = r.iter().map(|rm| rm.metrics.len()).sum();
-                let resource_count = r.len();
-                if resource_count == 0 {
-                    warn!("TSPoller '{}': poll returned empty batch this tick", config.name);
-                } else {
-                    debug!(
-                        "TSPoller '{}': sending {} resources, {} metrics",
-                        config.name, resource_count, metric_count
-                    );
-                }
-                add_start_time(&mut r, start_time);
-                if let Err(err) = tx.send(r).await {
-                    error!("metrics receiver thread dropped: {}", err);
-                }
-            } else if let Err(err) = poll_result {
-                error!("error while polling Intersight: {}", err);
             }
+
+            // Retry failed windows after the poll interval, otherwise wait for
+            // the next window to be complete. With an adaptive lag the next
+            // window is complete once TimeBoundary says so, which is checked
+            // every poll interval after the window has ended.
+            let next_end = latest.end + chrono::Duration::seconds(config.alignment() as i64);
+            let wait = if failed {
+                time::Duration::from_secs(config.interval())
+            } else if config.adaptive_lag() {
+                let interval = time::Duration::from_secs(config.interval());
+                (next_end - Utc::now()).to_std().unwrap_or_default() + interval
+            } else {
+                let available_at = next_end + chrono::Duration::seconds(config.lag() as i64);
+                (available_at - Utc::now()).to_std().unwrap_or_default()
+            };
+            time::sleep(wait).await;
         }
     });
 
     Ok(handle)
 }
 
+/// The number of times in a row `window` has failed, including this failure,
+/// given the window that failed before and how many times
+fn failed_attempts(failing: Option<(QueryWindow, u32)>, window: QueryWindow) -> u32 {
+    match failing {
+        Some((failed, attempts)) if failed == window => attempts + 1,
+        _ => 1,
+    }
+}
+
+/// The most recent complete window for a timeseries poller. With an adaptive
+/// lag, this is the latest window that ends before the datasource's latest
+/// data according to Druid's TimeBoundary, and the freshness lag metric for
+/// the datasource is returned as well. Falls back to the configured lag if the
+/// TimeBoundary query fails.
+async fn latest_window(
+    client: &CoalescingClient,
+    config: &TSPollerConfig,
+    now: DateTime<Utc>,
+) -> (QueryWindow, Option<IntersightResourceMetrics>) {
+    if config.adaptive_lag() {
+        match timeseries_poller::latest_data_time(client, &config.datasource).await {
+            Ok(max_time) => {
+                let window = QueryWindow::latest(max_time, 0, config.window(), config.alignment());
+                let freshness = timeseries_poller::freshness_metrics(
+                    &config.name,
+                    &config.datasource,
+                    max_time,
+                    now,
+                );
+                return (window, Some(freshness));
+            }
+            Err(err) => warn!(
+                "TSPoller '{}': unable to get time boundary of {}, using the configured lag: {}",
+                config.name, config.datasource, err
+            ),
+        }
+    }
+
+    let window = QueryWindow::latest(now, config.lag(), config.window(), config.alignment());
+    (window, None)
+}
+
+/// Polls Intersight once for the given poller (including attributes and
+/// enrichment) and returns the resulting batch instead of sending it.
+pub async fn run_intersight_poller_once(
+    client: &CoalescingClient,
+    config: &PollerConfig,
+    enrichers: &[Arc<AttributeEnricher>],
+) -> Result<IntersightMetricBatch> {
+    check_page_size(config)?;
+    let aggregator = get_aggregator_for_config(config)?;
+    run_poller(
+        client,
+        config,
+        aggregator.as_ref(),
+        enrichers,
+        SystemTime::now(),
+    )
+    .await
+}
+
+/// Polls Intersight once for the given timeseries poller (including attributes
+/// and enrichment) and returns the resulting batch instead of sending it.
+pub async fn run_intersight_tspoller_once(
+    client: &CoalescingClient,
+    config: &TSPollerConfig,
+    enrichers: &[Arc<AttributeEnricher>],
+) -> Result<IntersightMetricBatch> {
+    let (window, freshness) = latest_window(client, config, Utc::now()).await;
+    let mut r = run_tspoller(client, config, enrichers, window, SystemTime::now()).await?;
+    r.extend(freshness);
+    Ok(r)
+}
+
+/// Polls Intersight for one window of the given timeseries poller (including
+/// attributes and enrichment) and returns the resulting batch.
+pub async fn run_intersight_tspoller_window(
+    client: &CoalescingClient,
+    config: &TSPollerConfig,
+    enrichers: &[Arc<AttributeEnricher>],
+    window: QueryWindow,
+) -> Result<IntersightMetricBatch> {
+    run_tspoller(client, config, enrichers, window, SystemTime::now()).await
+}
+
+async fn run_poller(
+    client: &CoalescingClient,
+    config: &PollerConfig,
+    aggregator: &(dyn Aggregator + Sync + Send),
+    enrichers: &[Arc<AttributeEnricher>],
+    start_time: SystemTime,
+) -> Result<IntersightMetricBatch> {
+    let mut r = generic_poller::poll(
+        client,
+        &config.api_query,
+        &config.api_method,
+        &config.api_body,
+        config.page_size,
+        aggregator,
+    )
+    .await?;
+
+    if config.metrics.is_none() {
+        set_metric_kind(&mut r, poller_metric_kind(config)?, config.interval());
+        // Keep any unit or description the aggregator set unless configured
+        for m in r.iter_mut().flat_map(|rm| rm.metrics.iter_mut()) {
+            if let Some(unit) = &config.unit {
+                m.unit.clone_from(unit);
+            }
+            if let Some(description) = &config.description {
+                m.description.clone_from(description);
+            }
+        }
+    }
+    add_otel_attributes(&mut r, config);
+    enrich_batch("Poller", &config.name, &mut r, enrichers).await;
+    move_datapoint_attributes(&mut r, config);
+    log_batch("Poller", &config.name, &r);
+    add_start_time(&mut r, start_time);
+
+    Ok(r)
+}
+
+async fn run_tspoller(
+    client: &CoalescingClient,
+    config: &TSPollerConfig,
+    enrichers: &[Arc<AttributeEnricher>],
+    window: QueryWindow,
+    start_time: SystemTime,
+) -> Result<IntersightMetricBatch> {
+    let mut r = timeseries_poller::poll_window(client, config, window).await?;
+
+    add_otel_attributes(&mut r, config);
+    enrich_batch("TSPoller", &config.name, &mut r, enrichers).await;
+    move_datapoint_attributes(&mut r, config);
+    log_batch("TSPoller", &config.name, &r);
+    add_start_time(&mut r, start_time);
+
+    Ok(r)
+}
+
+async fn enrich_batch(
+    kind: &str,
+    name: &str,
+    batch: &mut IntersightMetricBatch,
+    enrichers: &[Arc<AttributeEnricher>],
+) {
+    if enrichers.is_empty() {
+        return;
+    }
+
+    let enrich_result = time::timeout(time::Duration::from_secs(ENRICH_TIMEOUT_SECS), async {
+        for enricher in enrichers {
+            enricher.enrich_batch(batch).await;
+        }
+    })
+    .await;
+    if enrich_result.is_err() {
+        warn!(
+            "{} '{}': enrichment timed out after {}s, sending batch un-enriched",
+            kind, name, ENRICH_TIMEOUT_SECS
+        );
+    }
+}
+
+fn log_batch(kind: &str, name: &str, batch: &IntersightMetricBatch) {
+    let metric_count: usize = batch.iter().map(|rm| rm.metrics.len()).sum();
+    let resource_count = batch.len();
+    if resource_count == 0 {
+        warn!("{} '{}': poll returned empty batch this tick", kind, name);
+    } else {
+        debug!(
+            "{} '{}': sending {} resources, {} metrics",
+            kind, name, resource_count, metric_count
+        );
+    }
+}
+
+/// Adds the poller's static attributes to every resource. Attributes the
+/// aggregator already set, such as a metric's own `otel_attributes`, win.
 fn add_otel_attributes(batch: &mut IntersightMetricBatch, config: &impl OTelAttributeProvider) {
     for metrics in batch {
         for (k, v) in config.otel_attributes() {
+            if metrics.attributes.iter().any(|kv| kv.key == k) {
+                continue;
+            }
             metrics.attributes.push(KeyValue {
                 key: k,
                 value: Some(AnyValue {
@@ -206,8 +574,315 @@ fn add_otel_attributes(batch: &mut IntersightMetricBatch, config: &impl OTelAttr
     }
 }
 
+/// Moves the configured `datapoint_attributes` from the resources of the batch
+/// to their metrics, after enrichment so enriched attributes can be moved too.
+/// Resources that only differed by those attributes are then merged, so e.g.
+/// the Druid rows of one host for each interface share the host's resource.
+fn move_datapoint_attributes(
+    batch: &mut IntersightMetricBatch,
+    config: &impl OTelAttributeProvider,
+) {
+    let names = config.datapoint_attributes();
+    if names.is_empty() {
+        return;
+    }
+
+    let mut merged: IntersightMetricBatch = vec![];
+    let mut index: HashMap<Vec<(String, String)>, usize> = HashMap::new();
+    for mut rm in batch.drain(..) {
+        let (moved, kept): (Vec<KeyValue>, Vec<KeyValue>) = rm
+            .attributes
+            .drain(..)
+            .partition(|kv| names.contains(&kv.key));
+        rm.attributes = kept;
+        for m in &mut rm.metrics {
+            for kv in &moved {
+                if !m.attributes.contains_key(&kv.key) {
+                    m.datapoint_attributes.push(kv.clone());
+                }
+            }
+        }
+
+        match index.entry(resource_key(&rm.attributes)) {
+            Entry::Occupied(entry) => merged[*entry.get()].metrics.append(&mut rm.metrics),
+            Entry::Vacant(entry) => {
+                entry.insert(merged.len());
+                merged.push(rm);
+            }
+        }
+    }
+    *batch = merged;
+}
+
+/// Identifies a resource by its attributes regardless of their order.
+/// KeyValue isn't hashable, so values are keyed by their JSON encoding, which
+/// keeps their type (`1` and `"1"` differ).
+fn resource_key(attributes: &[KeyValue]) -> Vec<(String, String)> {
+    let mut key: Vec<(String, String)> = attributes
+        .iter()
+        .map(|kv| {
+            let value = serde_json::to_string(&kv.value).unwrap_or_default();
+            (kv.key.clone(), value)
+        })
+        .collect();
+    key.sort();
+    key
+}
+
+/// The kind of the metrics of a poller, which can only be a gauge or a sum
+pub fn poller_metric_kind(config: &PollerConfig) -> Result<MetricKind> {
+    MetricKind::scalar(
+        config.kind.as_deref(),
+        config.temporality.as_deref(),
+        config.monotonic,
+    )
+}
+
+/// Sets the kind of every metric in the batch. Delta sums of generic pollers
+/// cover the poll interval before the data point.
+fn set_metric_kind(batch: &mut IntersightMetricBatch, kind: MetricKind, interval: u64) {
+    if kind == MetricKind::Gauge {
+        return;
+    }
+    for m in batch.iter_mut().flat_map(|rm| rm.metrics.iter_mut()) {
+        if let MetricKind::Sum {
+            cumulative: false, ..
+        } = kind
+        {
+            m.start_time = m
+                .time
+                .checked_sub(time::Duration::from_secs(interval))
+                .unwrap_or(m.time);
+        }
+        m.kind = kind.clone();
+    }
+}
+
 fn add_start_time(batch: &mut IntersightMetricBatch, start_time: SystemTime) {
     for metrics in batch {
         metrics.start_time = Some(start_time);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use serde_json::{json, Value};
+
+    struct ConstantAggregator(f64);
+
+    impl Aggregator for ConstantAggregator {
+        fn aggregate(&self, _r: Value) -> IntersightMetricBatch {
+            vec![IntersightResourceMetrics {
+                metrics: vec![IntersightMetric::new(
+                    "constant",
+                    self.0,
+                    None,
+                    SystemTime::now(),
+                    SystemTime::now(),
+                )],
+                ..Default::default()
+            }]
+        }
+    }
+
+    fn poller_config(aggregator: &str, options: Value) -> PollerConfig {
+        serde_json::from_value(json!({
+            "name": "m",
+            "api_query": "api/v1/cond/Alarms",
+            "aggregator": aggregator,
+            "aggregator_options": options,
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_register_aggregator() {
+        #[derive(serde::Deserialize)]
+        struct Options {
+            value: f64,
+        }
+        register_aggregator("test_constant", |config| {
+            let options: Options = parse_options(config)?;
+            Ok(Box::new(ConstantAggregator(options.value)))
+        });
+
+        let aggregator =
+            get_aggregator_for_config(&poller_config("test_constant", json!({"value": 42.0})))
+                .unwrap();
+        let batch = aggregator.aggregate(Value::Null);
+        assert_eq!(batch[0].metrics[0].value, 42.0);
+
+        assert!(
+            get_aggregator_for_config(&poller_config("test_constant", json!({"other": 1})))
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn test_builtin_and_unknown_aggregators() {
+        assert!(get_aggregator_for_config(&poller_config("count_results", json!({}))).is_ok());
+        let err = get_aggregator_for_config(&poller_config("nope", json!({})))
+            .err()
+            .unwrap();
+        assert_eq!(err.to_string(), "Invalid aggregator nope");
+    }
+
+    #[test]
+    fn test_failed_window_attempts() {
+        let now = Utc.with_ymd_and_hms(2025, 1, 15, 14, 37, 45).unwrap();
+        let window = QueryWindow::latest(now, 900, 600, 600);
+        let next = QueryWindow::latest(now + chrono::Duration::minutes(10), 900, 600, 600);
+
+        assert_eq!(failed_attempts(None, window), 1);
+        assert_eq!(failed_attempts(Some((window, 1)), window), 2);
+        // A different window starts counting again
+        assert_eq!(failed_attempts(Some((window, 2)), next), 1);
+    }
+
+    #[test]
+    fn test_metric_otel_attributes_override_poller_ones() {
+        let attribute = |key: &str, value: &str| KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(value.to_string())),
+            }),
+        };
+        let config: PollerConfig = serde_json::from_value(json!({
+            "name": "alarms",
+            "api_query": "api/v1/cond/Alarms",
+            "otel_attributes": {"severity": "any", "source": "intersight"},
+            "metrics": [
+                {"name": "alarms.critical", "aggregator": "count_results",
+                 "otel_attributes": {"severity": "critical"}},
+                {"name": "alarms.all", "aggregator": "count_results"},
+            ],
+        }))
+        .unwrap();
+
+        let aggregator = get_aggregator_for_config(&config).unwrap();
+        let mut batch = aggregator.aggregate(json!({"Results": [{}]}));
+        add_otel_attributes(&mut batch, &config);
+        for rm in &mut batch {
+            rm.attributes.sort_by(|a, b| a.key.cmp(&b.key));
+        }
+
+        assert_eq!(
+            batch[0].attributes,
+            vec![
+                attribute("severity", "critical"),
+                attribute("source", "intersight")
+            ]
+        );
+        assert_eq!(
+            batch[1].attributes,
+            vec![
+                attribute("severity", "any"),
+                attribute("source", "intersight")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_metric_unit_and_description_default_to_poller() {
+        let config: PollerConfig = serde_json::from_value(json!({
+            "name": "vms",
+            "api_query": "api/v1/virtualization/VirtualMachines",
+            "unit": "{vms}",
+            "description": "Number of VMs",
+            "metrics": [
+                {"name": "vms.all", "aggregator": "count_results"},
+                {"name": "vms.ratio", "aggregator": "count_results", "unit": "1",
+                 "description": "Share of VMs"},
+            ],
+        }))
+        .unwrap();
+
+        let batch = get_aggregator_for_config(&config)
+            .unwrap()
+            .aggregate(json!({"Results": [{}]}));
+        let metrics: Vec<(&str, &str)> = batch
+            .iter()
+            .flat_map(|rm| &rm.metrics)
+            .map(|m| (m.unit.as_str(), m.description.as_str()))
+            .collect();
+        assert_eq!(metrics, [("{vms}", "Number of VMs"), ("1", "Share of VMs")]);
+    }
+
+    #[test]
+    fn test_move_datapoint_attributes() {
+        let attribute = |key: &str, value: &str| KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(value.to_string())),
+            }),
+        };
+        let resource = |host: &str, severity: &str| IntersightResourceMetrics {
+            attributes: vec![attribute("host.id", host), attribute("severity", severity)],
+            metrics: vec![IntersightMetric::new(
+                "alarms",
+                1.0,
+                None,
+                SystemTime::now(),
+                SystemTime::now(),
+            )],
+            ..Default::default()
+        };
+        let mut batch = vec![
+            resource("a", "critical"),
+            resource("a", "warning"),
+            resource("b", "critical"),
+        ];
+
+        // Nothing moves unless configured
+        let mut config = poller_config("count_results", json!({}));
+        move_datapoint_attributes(&mut batch, &config);
+        assert_eq!(batch.len(), 3);
+
+        config.datapoint_attributes = Some(vec!["severity".to_string()]);
+        move_datapoint_attributes(&mut batch, &config);
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[0].attributes, vec![attribute("host.id", "a")]);
+        let severities: Vec<&[KeyValue]> = batch[0]
+            .metrics
+            .iter()
+            .map(|m| m.datapoint_attributes.as_slice())
+            .collect();
+        assert_eq!(
+            severities,
+            [
+                [attribute("severity", "critical")],
+                [attribute("severity", "warning")]
+            ]
+        );
+        assert_eq!(batch[1].attributes, vec![attribute("host.id", "b")]);
+        assert_eq!(
+            batch[1].metrics[0].datapoint_attributes,
+            [attribute("severity", "critical")]
+        );
+
+        // Resources are merged regardless of the order of their attributes
+        let mut batch = vec![resource("a", "critical"), resource("a", "warning")];
+        batch[1].attributes.reverse();
+        config.datapoint_attributes = Some(vec!["severity".to_string()]);
+        batch[0].attributes.push(attribute("rack", "1"));
+        batch[1].attributes.insert(0, attribute("rack", "1"));
+        move_datapoint_attributes(&mut batch, &config);
+        assert_eq!(batch.len(), 1);
+        assert_eq!(batch[0].metrics.len(), 2);
+
+        // Moved attributes keep their type
+        let port = KeyValue {
+            key: "port".to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::IntValue(1)),
+            }),
+        };
+        let mut batch = vec![resource("a", "critical")];
+        batch[0].attributes.push(port.clone());
+        config.datapoint_attributes = Some(vec!["port".to_string()]);
+        move_datapoint_attributes(&mut batch, &config);
+        assert_eq!(batch[0].metrics[0].datapoint_attributes, [port]);
+    }
+}
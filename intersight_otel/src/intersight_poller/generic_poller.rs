@@ -1,13 +1,24 @@
-use super::{IntersightMetric, IntersightMetricBatch, IntersightResourceMetrics};
-use intersight_api::{Client, IntersightError};
+use super::{IntersightMetric, IntersightMetricBatch, IntersightResourceMetrics, MetricKind};
+use crate::coalescing_client::CoalescingClient;
+use crate::config::{PollerConfig, PollerMetricConfig};
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Utc};
+use intersight_api::IntersightError;
+use opentelemetry_proto::tonic::common::v1::{any_value, AnyValue, KeyValue};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
 use serde_json::Value;
+use serde_json_path::JsonPath;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
 use std::time::SystemTime;
 
 pub async fn poll(
-    client: &Client,
+    client: &CoalescingClient,
     query: &str,
     method: &Option<String>,
     body: &Option<String>,
+    page_size: Option<u64>,
     agg: &(dyn Aggregator + Sync + Send),
 ) -> Result<IntersightMetricBatch, PollerError> {
     let method = (*method).clone().unwrap_or_default();
@@ -22,11 +33,12 @@ pub async fn poll(
         _ => serde_json::from_str(body).map_err(|_| PollerError::ConfigError)?,
     };
 
-    let response = match method {
-        "post" => client
+    let response = match (method, page_size) {
+        ("post", _) => client
             .post(query, body)
             .await
             .map_err(PollerError::APIError)?,
+        (_, Some(page_size)) => get_all_pages(client, query, page_size).await?,
         _ => client.get(query).await.map_err(PollerError::APIError)?,
     };
 
@@ -35,15 +47,72 @@ pub async fn poll(
     Ok(ret)
 }
 
+// Fetches every page of a GET query using $top/$skip and returns the first
+// response with the `Results` of all pages merged into it
+/// Upper bound on the pages fetched for one poll
+const MAX_PAGES: u64 = 1000;
+
+async fn get_all_pages(
+    client: &CoalescingClient,
+    query: &str,
+    page_size: u64,
+) -> Result<Value, PollerError> {
+    let mut response = Value::Null;
+    let mut results = vec![];
+    let mut skip = 0;
+    let mut pages = 0;
+
+    loop {
+        let mut page = client
+            .get(&paged_query(query, page_size, skip))
+            .await
+            .map_err(PollerError::APIError)?;
+        let page_results = match page["Results"].take() {
+            Value::Array(r) => r,
+            _ => vec![],
+        };
+        let page_len = page_results.len() as u64;
+        results.extend(page_results);
+        if response.is_null() {
+            response = page;
+        }
+
+        if page_len < page_size {
+            break;
+        }
+        pages += 1;
+        if pages >= MAX_PAGES {
+            // Guards against endpoints that ignore $skip and keep returning
+            // the same full page
+            warn!(
+                "query {} returned more than {} pages, stopping pagination",
+                query, MAX_PAGES
+            );
+            break;
+        }
+        skip += page_size;
+    }
+
+    response["Results"] = Value::Array(results);
+    Ok(response)
+}
+
+fn paged_query(query: &str, top: u64, skip: u64) -> String {
+    let separator = if query.contains('?') { '&' } else { '?' };
+    format!("{}{}$top={}&$skip={}", query, separator, top, skip)
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum PollerError {
-    #[error("error calling Intersight API")]
-    APIError(IntersightError),
+    #[error("error calling Intersight API: {0}")]
+    APIError(Arc<IntersightError>),
 
     #[error("poller configuration error")]
     ConfigError,
 }
 
+/// Turns an Intersight API response into metrics. Custom aggregators can be
+/// added with [`register_aggregator`](super::register_aggregator).
 pub trait Aggregator {
     fn aggregate(&self, r: Value) -> IntersightMetricBatch;
 }
@@ -121,3 +190,1524 @@ impl Aggregator for ResultCountAggregator {
         vec![ret]
     }
 }
+
+/// Deserializes a poller's `aggregator_options` into the options struct for its aggregator
+pub fn parse_options<T: DeserializeOwned>(config: &PollerConfig) -> Result<T> {
+    let options = serde_json::to_value(config.aggregator_options.clone().unwrap_or_default())?;
+    serde_json::from_value(options).with_context(|| {
+        format!(
+            "invalid aggregator_options for aggregator {}",
+            config.aggregator
+        )
+    })
+}
+
+fn value_to_string(v: &Value) -> String {
+    v.as_str()
+        .map(String::from)
+        .unwrap_or_else(|| v.to_string())
+}
+
+fn string_attribute(key: &str, value: String) -> KeyValue {
+    KeyValue {
+        key: key.to_string(),
+        value: Some(AnyValue {
+            value: Some(any_value::Value::StringValue(value)),
+        }),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct GroupByOptions {
+    value_field: Option<String>,
+    group_by: HashMap<String, String>,
+}
+
+//GroupByAggregator emits one data point per result row of an OData $apply=groupby(...) query,
+//reading the value from `value_field` and turning the group keys into attributes
+pub struct GroupByAggregator {
+    name: String,
+    value_field: String,
+    value_path: JsonPath,
+    group_by: AttributeMappings,
+}
+
+impl GroupByAggregator {
+    pub fn new(name: String, options: GroupByOptions) -> Result<GroupByAggregator> {
+        let value_field = options.value_field.unwrap_or_else(|| "count".to_string());
+        let group_by = options
+            .group_by
+            .into_iter()
+            .map(|(field, attribute)| (field_path(&field), attribute))
+            .collect();
+        Ok(GroupByAggregator {
+            name,
+            value_path: parse_json_path(&field_path(&value_field))?,
+            value_field,
+            group_by: AttributeMappings::new(group_by)?,
+        })
+    }
+}
+
+/// Group-by results have flat keys, so a bare name such as `Severity` is
+/// accepted as a shorthand for `$.Severity`
+fn field_path(field: &str) -> String {
+    if field.starts_with('$') {
+        field.to_string()
+    } else {
+        format!("$.{}", field)
+    }
+}
+
+impl Aggregator for GroupByAggregator {
+    fn aggregate(&self, r: Value) -> IntersightMetricBatch {
+        let Value::Array(results) = &r["Results"] else {
+            warn!("'Results' field not present in API response");
+            return vec![];
+        };
+
+        let mut ret = vec![];
+        for result in results {
+            let Some(value) = query_first(&self.value_path, result).and_then(Value::as_f64) else {
+                warn!(
+                    "Value field '{}' missing or not numeric in result, skipping: {}",
+                    self.value_field, result
+                );
+                continue;
+            };
+
+            let mut rm = IntersightResourceMetrics {
+                attributes: self.group_by.attributes(result),
+                ..Default::default()
+            };
+            rm.metrics.push(IntersightMetric::new(
+                &self.name,
+                value,
+                None,
+                SystemTime::now(),
+                SystemTime::now(),
+            ));
+            ret.push(rm);
+        }
+
+        ret
+    }
+}
+
+fn value_to_f64(v: &Value) -> Option<f64> {
+    match v {
+        Value::Number(n) => n.as_f64(),
+        Value::String(s) => s.parse().ok(),
+        _ => None,
+    }
+}
+
+fn parse_json_path(path: &str) -> Result<JsonPath> {
+    JsonPath::parse(path).with_context(|| format!("invalid JSONPath '{}'", path))
+}
+
+fn query_first<'a>(path: &JsonPath, value: &'a Value) -> Option<&'a Value> {
+    path.query(value).first().filter(|v| !v.is_null())
+}
+
+/// JSONPath to attribute name mappings applied to each object in `Results`
+pub struct AttributeMappings {
+    mappings: Vec<(JsonPath, String)>,
+}
+
+impl AttributeMappings {
+    pub fn new(map: HashMap<String, String>) -> Result<AttributeMappings> {
+        let mut map: Vec<(String, String)> = map.into_iter().collect();
+        map.sort();
+        let mappings = map
+            .into_iter()
+            .map(|(path, attribute)| Ok((parse_json_path(&path)?, attribute)))
+            .collect::<Result<_>>()?;
+        Ok(AttributeMappings { mappings })
+    }
+
+    fn attributes(&self, object: &Value) -> Vec<KeyValue> {
+        self.mappings
+            .iter()
+            .filter_map(|(path, attribute)| {
+                query_first(path, object).map(|v| string_attribute(attribute, value_to_string(v)))
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FieldValueOptions {
+    value: String,
+    attributes: Option<HashMap<String, String>>,
+}
+
+//FieldValueAggregator emits one data point per object in `Results`, reading the value with a
+//JSONPath and turning the configured JSONPath attribute mappings into resource attributes
+pub struct FieldValueAggregator {
+    name: String,
+    value: JsonPath,
+    attributes: AttributeMappings,
+}
+
+impl FieldValueAggregator {
+    pub fn new(name: String, options: FieldValueOptions) -> Result<FieldValueAggregator> {
+        Ok(FieldValueAggregator {
+            name,
+            value: parse_json_path(&options.value)?,
+            attributes: AttributeMappings::new(options.attributes.unwrap_or_default())?,
+        })
+    }
+}
+
+impl Aggregator for FieldValueAggregator {
+    fn aggregate(&self, r: Value) -> IntersightMetricBatch {
+        let Value::Array(results) = &r["Results"] else {
+            warn!("'Results' field not present in API response");
+            return vec![];
+        };
+
+        let mut ret = vec![];
+        for result in results {
+            let Some(value) = query_first(&self.value, result).and_then(value_to_f64) else {
+                debug!(
+                    "Value '{}' missing or not numeric in result, skipping",
+                    self.value
+                );
+                continue;
+            };
+
+            ret.push(IntersightResourceMetrics {
+                attributes: self.attributes.attributes(result),
+                metrics: vec![IntersightMetric::new(
+                    &self.name,
+                    value,
+                    None,
+                    SystemTime::now(),
+                    SystemTime::now(),
+                )],
+                ..Default::default()
+            });
+        }
+
+        ret
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Statistic {
+    Sum,
+    Mean,
+    Min,
+    Max,
+    Count,
+    Percentile(f64),
+}
+
+impl Statistic {
+    fn parse(op: &str) -> Result<Statistic> {
+        Ok(match op {
+            "sum" => Statistic::Sum,
+            "mean" => Statistic::Mean,
+            "min" => Statistic::Min,
+            "max" => Statistic::Max,
+            "count" => Statistic::Count,
+            _ => match op.strip_prefix('p').and_then(|p| p.parse::<f64>().ok()) {
+                Some(p) if (0.0..=100.0).contains(&p) => Statistic::Percentile(p),
+                _ => bail!(
+                    "invalid operation '{}', expected sum, mean, min, max, count or pNN",
+                    op
+                ),
+            },
+        })
+    }
+
+    // values must be sorted
+    fn compute(&self, values: &[f64]) -> Option<f64> {
+        if values.is_empty() {
+            return match self {
+                Statistic::Sum | Statistic::Count => Some(0.0),
+                _ => None,
+            };
+        }
+        let sum: f64 = values.iter().sum();
+        Some(match self {
+            Statistic::Sum => sum,
+            Statistic::Mean => sum / values.len() as f64,
+            Statistic::Min => values[0],
+            Statistic::Max => values[values.len() - 1],
+            Statistic::Count => values.len() as f64,
+            Statistic::Percentile(p) => {
+                // Linear interpolation between the closest ranks
+                let rank = p / 100.0 * (values.len() - 1) as f64;
+                let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+                values[lower] + (values[upper] - values[lower]) * (rank - lower as f64)
+            }
+        })
+    }
+}
+
+#[derive(Deserialize)]
+pub struct StatsOptions {
+    field: String,
+    operations: Vec<String>,
+    group_by: Option<String>,
+    group_by_attribute: Option<String>,
+}
+
+//StatsAggregator computes statistics (sum, mean, min, max, count, percentiles) of a numeric field
+//across all objects in `Results`, optionally grouped by another field, emitting one metric per
+//operation named `<name>.<operation>`
+pub struct StatsAggregator {
+    name: String,
+    field: JsonPath,
+    operations: Vec<(Statistic, String)>,
+    group_by: Option<(JsonPath, String)>,
+}
+
+impl StatsAggregator {
+    pub fn new(name: String, options: StatsOptions) -> Result<StatsAggregator> {
+        if options.operations.is_empty() {
+            bail!("at least one operation is required");
+        }
+        let operations = options
+            .operations
+            .into_iter()
+            .map(|op| Ok((Statistic::parse(&op)?, op)))
+            .collect::<Result<_>>()?;
+        let group_by = match options.group_by {
+            Some(path) => {
+                let attribute = options
+                    .group_by_attribute
+                    .unwrap_or_else(|| path.trim_start_matches("$.").to_string());
+                Some((parse_json_path(&path)?, attribute))
+            }
+            None => None,
+        };
+
+        Ok(StatsAggregator {
+            name,
+            field: parse_json_path(&options.field)?,
+            operations,
+            group_by,
+        })
+    }
+}
+
+impl Aggregator for StatsAggregator {
+    fn aggregate(&self, r: Value) -> IntersightMetricBatch {
+        let Value::Array(results) = &r["Results"] else {
+            warn!("'Results' field not present in API response");
+            return vec![];
+        };
+
+        let mut groups: BTreeMap<Option<String>, Vec<f64>> = BTreeMap::new();
+        if self.group_by.is_none() {
+            groups.insert(None, vec![]);
+        }
+        for result in results {
+            let group = match &self.group_by {
+                Some((path, _)) => {
+                    let Some(group) = query_first(path, result) else {
+                        debug!("Group '{}' missing in result, skipping", path);
+                        continue;
+                    };
+                    Some(value_to_string(group))
+                }
+                None => None,
+            };
+            let values = groups.entry(group).or_default();
+            if let Some(v) = query_first(&self.field, result).and_then(value_to_f64) {
+                values.push(v);
+            }
+        }
+
+        let mut ret = vec![];
+        for (group, mut values) in groups {
+            values.sort_by(f64::total_cmp);
+
+            let mut rm = IntersightResourceMetrics::default();
+            if let (Some(group), Some((_, attribute))) = (group, &self.group_by) {
+                rm.attributes.push(string_attribute(attribute, group));
+            }
+            for (statistic, op) in &self.operations {
+                if let Some(value) = statistic.compute(&values) {
+                    rm.metrics.push(IntersightMetric::new(
+                        &format!("{}.{}", self.name, op),
+                        value,
+                        None,
+                        SystemTime::now(),
+                        SystemTime::now(),
+                    ));
+                }
+            }
+            if !rm.metrics.is_empty() {
+                ret.push(rm);
+            }
+        }
+
+        ret
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum StateMode {
+    #[default]
+    Count,
+    StateSet,
+    Enum,
+}
+
+#[derive(Deserialize)]
+pub struct StateOptions {
+    field: String,
+    mode: Option<StateMode>,
+    states: Option<Vec<String>>,
+    state_attribute: Option<String>,
+    value_map: Option<HashMap<String, f64>>,
+    attributes: Option<HashMap<String, String>>,
+}
+
+//StateAggregator turns a string field (e.g. OperState) into metrics. In `count` mode it counts
+//the objects per distinct value, in `state_set` mode it emits a 0/1 gauge per object and state
+//(the OpenMetrics StateSet convention) and in `enum` mode it maps each object's value to a number
+pub struct StateAggregator {
+    name: String,
+    field: JsonPath,
+    mode: StateMode,
+    states: Vec<String>,
+    state_attribute: String,
+    value_map: HashMap<String, f64>,
+    attributes: AttributeMappings,
+}
+
+impl StateAggregator {
+    pub fn new(name: String, options: StateOptions) -> Result<StateAggregator> {
+        let mode = options.mode.unwrap_or_default();
+        if mode == StateMode::Enum && options.value_map.is_none() {
+            bail!("value_map is required in enum mode");
+        }
+        if mode == StateMode::Count && options.attributes.is_some() {
+            bail!("attributes are not supported in count mode");
+        }
+
+        Ok(StateAggregator {
+            field: parse_json_path(&options.field)?,
+            mode,
+            states: options.states.unwrap_or_default(),
+            state_attribute: options.state_attribute.unwrap_or_else(|| name.clone()),
+            value_map: options.value_map.unwrap_or_default(),
+            attributes: AttributeMappings::new(options.attributes.unwrap_or_default())?,
+            name,
+        })
+    }
+
+    fn metric(&self, value: f64) -> IntersightMetric {
+        IntersightMetric::new(
+            &self.name,
+            value,
+            None,
+            SystemTime::now(),
+            SystemTime::now(),
+        )
+    }
+
+    fn state_metrics(
+        &self,
+        mut attributes: Vec<KeyValue>,
+        state: String,
+        value: f64,
+    ) -> IntersightResourceMetrics {
+        attributes.push(string_attribute(&self.state_attribute, state));
+        IntersightResourceMetrics {
+            attributes,
+            metrics: vec![self.metric(value)],
+            ..Default::default()
+        }
+    }
+}
+
+impl Aggregator for StateAggregator {
+    fn aggregate(&self, r: Value) -> IntersightMetricBatch {
+        let Value::Array(results) = &r["Results"] else {
+            warn!("'Results' field not present in API response");
+            return vec![];
+        };
+
+        let states = results.iter().map(|result| {
+            let state = query_first(&self.field, result).map(value_to_string);
+            if state.is_none() {
+                debug!("Field '{}' missing in result, skipping", self.field);
+            }
+            (result, state)
+        });
+
+        let mut ret = vec![];
+        match self.mode {
+            StateMode::Count => {
+                // Known states are always reported so that they drop to zero
+                // rather than disappearing
+                let mut counts: BTreeMap<String, u64> =
+                    self.states.iter().map(|s| (s.clone(), 0)).collect();
+                for (_, state) in states {
+                    if let Some(state) = state {
+                        *counts.entry(state).or_default() += 1;
+                    }
+                }
+                for (state, count) in counts {
+                    ret.push(self.state_metrics(vec![], state, count as f64));
+                }
+            }
+            StateMode::StateSet => {
+                for (result, state) in states {
+                    let Some(state) = state else { continue };
+                    let attributes = self.attributes.attributes(result);
+                    for known in &self.states {
+                        let value = if *known == state { 1.0 } else { 0.0 };
+                        ret.push(self.state_metrics(attributes.clone(), known.clone(), value));
+                    }
+                    if !self.states.contains(&state) {
+                        ret.push(self.state_metrics(attributes, state, 1.0));
+                    }
+                }
+            }
+            StateMode::Enum => {
+                for (result, state) in states {
+                    let Some(state) = state else { continue };
+                    let Some(value) = self.value_map.get(&state) else {
+                        warn!(
+                            "Value '{}' of field '{}' not in value_map, skipping",
+                            state, self.field
+                        );
+                        continue;
+                    };
+                    ret.push(IntersightResourceMetrics {
+                        attributes: self.attributes.attributes(result),
+                        metrics: vec![self.metric(*value)],
+                        ..Default::default()
+                    });
+                }
+            }
+        }
+
+        ret
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AgeDirection {
+    #[default]
+    Since,
+    Until,
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum AgeReduce {
+    #[default]
+    None,
+    Min,
+    Max,
+}
+
+#[derive(Deserialize)]
+pub struct TimestampAgeOptions {
+    field: String,
+    direction: Option<AgeDirection>,
+    reduce: Option<AgeReduce>,
+    attributes: Option<HashMap<String, String>>,
+}
+
+//TimestampAgeAggregator parses an RFC 3339 timestamp in each object of `Results` and emits the
+//seconds since (or until) that time relative to the poll time, either per object or reduced to
+//the min/max across all objects
+pub struct TimestampAgeAggregator {
+    name: String,
+    field: JsonPath,
+    direction: AgeDirection,
+    reduce: AgeReduce,
+    attributes: AttributeMappings,
+}
+
+impl TimestampAgeAggregator {
+    pub fn new(name: String, options: TimestampAgeOptions) -> Result<TimestampAgeAggregator> {
+        let reduce = options.reduce.unwrap_or_default();
+        if reduce != AgeReduce::None && options.attributes.is_some() {
+            bail!("attributes are only supported when reduce is none");
+        }
+
+        Ok(TimestampAgeAggregator {
+            name,
+            field: parse_json_path(&options.field)?,
+            direction: options.direction.unwrap_or_default(),
+            reduce,
+            attributes: AttributeMappings::new(options.attributes.unwrap_or_default())?,
+        })
+    }
+
+    fn age(&self, object: &Value, now: DateTime<Utc>) -> Option<f64> {
+        let Some(Value::String(s)) = query_first(&self.field, object) else {
+            debug!(
+                "Field '{}' missing or not a string in result, skipping",
+                self.field
+            );
+            return None;
+        };
+        let time = match DateTime::parse_from_rfc3339(s) {
+            Ok(time) => time.with_timezone(&Utc),
+            Err(err) => {
+                warn!("Unable to parse '{}' as an RFC 3339 timestamp: {}", s, err);
+                return None;
+            }
+        };
+        // Intersight reports unset timestamps as 0001-01-01T00:00:00Z
+        if time <= DateTime::UNIX_EPOCH {
+            return None;
+        }
+
+        let age = match self.direction {
+            AgeDirection::Since => now - time,
+            AgeDirection::Until => time - now,
+        };
+        Some(age.num_milliseconds() as f64 / 1000.0)
+    }
+}
+
+impl Aggregator for TimestampAgeAggregator {
+    fn aggregate(&self, r: Value) -> IntersightMetricBatch {
+        let Value::Array(results) = &r["Results"] else {
+            warn!("'Results' field not present in API response");
+            return vec![];
+        };
+
+        let now = Utc::now();
+        let metric = |value| {
+            IntersightMetric::new(
+                &self.name,
+                value,
+                None,
+                SystemTime::now(),
+                SystemTime::now(),
+            )
+        };
+        let ages = results
+            .iter()
+            .filter_map(|result| self.age(result, now).map(|age| (result, age)));
+
+        let reduced = match self.reduce {
+            AgeReduce::None => {
+                return ages
+                    .map(|(result, age)| IntersightResourceMetrics {
+                        attributes: self.attributes.attributes(result),
+                        metrics: vec![metric(age)],
+                        ..Default::default()
+                    })
+                    .collect();
+            }
+            AgeReduce::Min => ages.map(|(_, age)| age).reduce(f64::min),
+            AgeReduce::Max => ages.map(|(_, age)| age).reduce(f64::max),
+        };
+
+        match reduced {
+            Some(age) => vec![IntersightResourceMetrics {
+                metrics: vec![metric(age)],
+                ..Default::default()
+            }],
+            None => vec![],
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub struct InfoOptions {
+    attributes: HashMap<String, String>,
+}
+
+//InfoAggregator emits a constant 1 `<name>_info` gauge per object in `Results`, carrying the
+//configured JSONPath fields as attributes (the Prometheus info-metric pattern)
+pub struct InfoAggregator {
+    name: String,
+    attributes: AttributeMappings,
+}
+
+impl InfoAggregator {
+    pub fn new(name: String, options: InfoOptions) -> Result<InfoAggregator> {
+        if options.attributes.is_empty() {
+            bail!("at least one attribute is required");
+        }
+        let name = if name.ends_with("_info") {
+            name
+        } else {
+            format!("{}_info", name)
+        };
+
+        Ok(InfoAggregator {
+            name,
+            attributes: AttributeMappings::new(options.attributes)?,
+        })
+    }
+}
+
+impl Aggregator for InfoAggregator {
+    fn aggregate(&self, r: Value) -> IntersightMetricBatch {
+        let Value::Array(results) = &r["Results"] else {
+            warn!("'Results' field not present in API response");
+            return vec![];
+        };
+
+        results
+            .iter()
+            .map(|result| IntersightResourceMetrics {
+                attributes: self.attributes.attributes(result),
+                metrics: vec![IntersightMetric::new(
+                    &self.name,
+                    1.0,
+                    None,
+                    SystemTime::now(),
+                    SystemTime::now(),
+                )],
+                ..Default::default()
+            })
+            .collect()
+    }
+}
+
+#[derive(Deserialize)]
+pub struct JqOptions {
+    filter: String,
+}
+
+//JqAggregator runs a jq filter over the raw API response. The filter must output an array of
+//`{name, value, attributes}` objects (or a stream of such objects), each of which becomes a data
+//point with `attributes` as resource attributes. `name` defaults to the poller name.
+pub struct JqAggregator {
+    name: String,
+    filter: jaq_core::Filter<jaq_core::Native<jaq_json::Val>>,
+}
+
+impl JqAggregator {
+    pub fn new(name: String, options: JqOptions) -> Result<JqAggregator> {
+        use jaq_core::load::{Arena, File, Loader};
+
+        let arena = Arena::default();
+        let loader = Loader::new(jaq_std::defs().chain(jaq_json::defs()));
+        let program = File {
+            code: options.filter.as_str(),
+            path: (),
+        };
+
+        let modules = loader.load(&arena, program).map_err(|errs| {
+            let messages: Vec<String> = errs
+                .into_iter()
+                .flat_map(|(_, err)| match err {
+                    jaq_core::load::Error::Io(errs) => {
+                        errs.into_iter().map(|(_, msg)| msg).collect::<Vec<_>>()
+                    }
+                    jaq_core::load::Error::Lex(errs) => errs
+                        .into_iter()
+                        .map(|(expect, at)| jq_error(expect.as_str(), at))
+                        .collect(),
+                    jaq_core::load::Error::Parse(errs) => errs
+                        .into_iter()
+                        .map(|(expect, at)| jq_error(expect.as_str(), at))
+                        .collect(),
+                })
+                .collect();
+            anyhow::anyhow!("invalid jq filter: {}", messages.join(", "))
+        })?;
+
+        let filter = jaq_core::Compiler::default()
+            .with_funs(jaq_std::funs().chain(jaq_json::funs()))
+            .compile(modules)
+            .map_err(|errs| {
+                let messages: Vec<String> = errs
+                    .into_iter()
+                    .flat_map(|(_, errs)| errs)
+                    .map(|(symbol, undefined)| {
+                        format!("undefined {} '{}'", undefined.as_str(), symbol)
+                    })
+                    .collect();
+                anyhow::anyhow!("invalid jq filter: {}", messages.join(", "))
+            })?;
+
+        Ok(JqAggregator { name, filter })
+    }
+
+    fn run(&self, r: Value) -> Result<Vec<Value>> {
+        use jaq_core::{Ctx, RcIter};
+
+        let inputs = RcIter::new(core::iter::empty());
+        let mut ret = vec![];
+        for output in self.filter.run((Ctx::new([], &inputs), r.into())) {
+            let output = output.map_err(|err| anyhow::anyhow!("jq filter failed: {}", err))?;
+            match Value::from(output) {
+                Value::Array(items) => ret.extend(items),
+                item => ret.push(item),
+            }
+        }
+        Ok(ret)
+    }
+
+    fn to_resource_metrics(&self, item: &Value) -> Result<IntersightResourceMetrics> {
+        let name = match &item["name"] {
+            Value::Null => self.name.as_str(),
+            Value::String(name) => name.as_str(),
+            other => bail!("'name' must be a string, got {}", other),
+        };
+        let Some(value) = value_to_f64(&item["value"]) else {
+            bail!("'value' missing or not numeric in {}", item);
+        };
+        let attributes = match &item["attributes"] {
+            Value::Null => vec![],
+            Value::Object(attributes) => attributes
+                .iter()
+                .filter(|(_, v)| !v.is_null())
+                .map(|(k, v)| string_attribute(k, value_to_string(v)))
+                .collect(),
+            other => bail!("'attributes' must be an object, got {}", other),
+        };
+
+        Ok(IntersightResourceMetrics {
+            attributes,
+            metrics: vec![IntersightMetric::new(
+                name,
+                value,
+                None,
+                SystemTime::now(),
+                SystemTime::now(),
+            )],
+            ..Default::default()
+        })
+    }
+}
+
+fn jq_error(expected: &str, at: &str) -> String {
+    match at.lines().next() {
+        Some(at) if !at.is_empty() => format!("expected {} at '{}'", expected, at),
+        _ => format!("expected {} at end of filter", expected),
+    }
+}
+
+impl Aggregator for JqAggregator {
+    fn aggregate(&self, r: Value) -> IntersightMetricBatch {
+        let items = match self.run(r) {
+            Ok(items) => items,
+            Err(err) => {
+                warn!("{}", err);
+                return vec![];
+            }
+        };
+
+        items
+            .iter()
+            .filter_map(|item| match self.to_resource_metrics(item) {
+                Ok(rm) => Some(rm),
+                Err(err) => {
+                    warn!("Skipping jq output item: {}", err);
+                    None
+                }
+            })
+            .collect()
+    }
+}
+
+/// An aggregator for one entry of a poller's `metrics`, adding the metric's
+/// attributes, and its unit and description if set, to everything it produces
+pub struct MetricAggregator {
+    aggregator: Box<dyn Aggregator + Sync + Send>,
+    unit: Option<String>,
+    description: Option<String>,
+    kind: MetricKind,
+    interval: u64,
+    attributes: Vec<KeyValue>,
+}
+
+impl MetricAggregator {
+    pub fn new(
+        aggregator: Box<dyn Aggregator + Sync + Send>,
+        config: &PollerMetricConfig,
+        kind: MetricKind,
+        interval: u64,
+    ) -> MetricAggregator {
+        let mut attributes: Vec<(String, String)> = config
+            .otel_attributes
+            .clone()
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+        attributes.sort();
+        MetricAggregator {
+            aggregator,
+            unit: config.unit.clone(),
+            description: config.description.clone(),
+            kind,
+            interval,
+            attributes: attributes
+                .into_iter()
+                .map(|(k, v)| string_attribute(&k, v))
+                .collect(),
+        }
+    }
+}
+
+impl Aggregator for MetricAggregator {
+    fn aggregate(&self, r: Value) -> IntersightMetricBatch {
+        let mut batch = self.aggregator.aggregate(r);
+        for rm in &mut batch {
+            rm.attributes.extend(self.attributes.iter().cloned());
+            for m in &mut rm.metrics {
+                if let Some(unit) = &self.unit {
+                    m.unit.clone_from(unit);
+                }
+                if let Some(description) = &self.description {
+                    m.description.clone_from(description);
+                }
+            }
+        }
+        super::set_metric_kind(&mut batch, self.kind.clone(), self.interval);
+        batch
+    }
+}
+
+//MultiAggregator computes several metrics from the same API response
+pub struct MultiAggregator {
+    aggregators: Vec<MetricAggregator>,
+}
+
+impl MultiAggregator {
+    pub fn new(aggregators: Vec<MetricAggregator>) -> MultiAggregator {
+        MultiAggregator { aggregators }
+    }
+}
+
+impl Aggregator for MultiAggregator {
+    fn aggregate(&self, r: Value) -> IntersightMetricBatch {
+        self.aggregators
+            .iter()
+            .flat_map(|aggregator| aggregator.aggregate(r.clone()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn attributes(rm: &IntersightResourceMetrics) -> Vec<(String, String)> {
+        rm.attributes
+            .iter()
+            .map(|kv| match &kv.value {
+                Some(AnyValue {
+                    value: Some(any_value::Value::StringValue(s)),
+                }) => (kv.key.clone(), s.clone()),
+                _ => panic!("unexpected attribute value"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_group_by_aggregator() {
+        let agg = GroupByAggregator::new(
+            "intersight.alarms.count".to_string(),
+            GroupByOptions {
+                value_field: None,
+                group_by: HashMap::from([
+                    ("Severity".to_string(), "severity".to_string()),
+                    ("$.Advisory.Moid".to_string(), "advisory.moid".to_string()),
+                ]),
+            },
+        )
+        .unwrap();
+
+        let batch = agg.aggregate(json!({"Results": [
+            {"Severity": "Critical", "Advisory": {"Moid": "a1"}, "count": 3},
+            {"Severity": "Warning", "count": 7},
+            {"Severity": "Info"},
+        ]}));
+
+        assert_eq!(batch.len(), 2);
+        assert_eq!(
+            attributes(&batch[0]),
+            vec![
+                ("advisory.moid".to_string(), "a1".to_string()),
+                ("severity".to_string(), "Critical".to_string()),
+            ]
+        );
+        assert_eq!(batch[0].metrics[0].value, 3.0);
+        assert_eq!(
+            attributes(&batch[1]),
+            vec![("severity".to_string(), "Warning".to_string())]
+        );
+        assert_eq!(batch[1].metrics[0].value, 7.0);
+    }
+
+    #[test]
+    fn test_group_by_aggregator_invalid_path() {
+        let agg = GroupByAggregator::new(
+            "m".to_string(),
+            GroupByOptions {
+                value_field: None,
+                group_by: HashMap::from([("$[".to_string(), "a".to_string())]),
+            },
+        );
+        assert!(agg.is_err());
+    }
+
+    #[test]
+    fn test_group_by_aggregator_no_results() {
+        let agg = GroupByAggregator::new(
+            "m".to_string(),
+            GroupByOptions {
+                value_field: Some("total".to_string()),
+                group_by: HashMap::new(),
+            },
+        )
+        .unwrap();
+        assert!(agg.aggregate(json!({"Count": 3})).is_empty());
+    }
+
+    #[test]
+    fn test_field_value_aggregator() {
+        let agg = FieldValueAggregator::new(
+            "intersight.server.memory.available".to_string(),
+            FieldValueOptions {
+                value: "$.AvailableMemory".to_string(),
+                attributes: Some(HashMap::from([
+                    ("$.Moid".to_string(), "host.id".to_string()),
+                    ("$.Serial".to_string(), "host.serial".to_string()),
+                ])),
+            },
+        )
+        .unwrap();
+
+        let batch = agg.aggregate(json!({"Results": [
+            {"Moid": "m1", "Serial": "S1", "AvailableMemory": 1024},
+            {"Moid": "m2", "AvailableMemory": "2048"},
+            {"Moid": "m3", "AvailableMemory": null},
+        ]}));
+
+        assert_eq!(batch.len(), 2);
+        assert_eq!(
+            attributes(&batch[0]),
+            vec![
+                ("host.id".to_string(), "m1".to_string()),
+                ("host.serial".to_string(), "S1".to_string()),
+            ]
+        );
+        assert_eq!(batch[0].metrics[0].value, 1024.0);
+        assert_eq!(
+            attributes(&batch[1]),
+            vec![("host.id".to_string(), "m2".to_string())]
+        );
+        assert_eq!(batch[1].metrics[0].value, 2048.0);
+    }
+
+    #[test]
+    fn test_field_value_aggregator_invalid_path() {
+        let agg = FieldValueAggregator::new(
+            "m".to_string(),
+            FieldValueOptions {
+                value: "AvailableMemory[".to_string(),
+                attributes: None,
+            },
+        );
+        assert!(agg.is_err());
+    }
+
+    fn metric_values(rm: &IntersightResourceMetrics) -> Vec<(String, f64)> {
+        rm.metrics
+            .iter()
+            .map(|m| (m.name.clone(), m.value))
+            .collect()
+    }
+
+    #[test]
+    fn test_statistic_percentile() {
+        let values = [1.0, 2.0, 3.0, 4.0, 5.0];
+        assert_eq!(Statistic::Percentile(50.0).compute(&values), Some(3.0));
+        assert_eq!(Statistic::Percentile(90.0).compute(&values), Some(4.6));
+        assert_eq!(Statistic::Percentile(0.0).compute(&values), Some(1.0));
+        assert_eq!(Statistic::Percentile(100.0).compute(&values), Some(5.0));
+        assert_eq!(Statistic::Percentile(99.0).compute(&[]), None);
+        assert_eq!(Statistic::Count.compute(&[]), Some(0.0));
+    }
+
+    #[test]
+    fn test_statistic_parse() {
+        assert_eq!(
+            Statistic::parse("p99").unwrap(),
+            Statistic::Percentile(99.0)
+        );
+        assert_eq!(Statistic::parse("mean").unwrap(), Statistic::Mean);
+        assert!(Statistic::parse("p101").is_err());
+        assert!(Statistic::parse("median").is_err());
+    }
+
+    #[test]
+    fn test_stats_aggregator() {
+        let agg = StatsAggregator::new(
+            "intersight.server.memory".to_string(),
+            StatsOptions {
+                field: "$.TotalMemory".to_string(),
+                operations: vec!["sum".into(), "mean".into(), "max".into(), "count".into()],
+                group_by: None,
+                group_by_attribute: None,
+            },
+        )
+        .unwrap();
+
+        let batch = agg.aggregate(json!({"Results": [
+            {"TotalMemory": 100},
+            {"TotalMemory": 300},
+            {"TotalMemory": null},
+        ]}));
+
+        assert_eq!(batch.len(), 1);
+        assert_eq!(
+            metric_values(&batch[0]),
+            vec![
+                ("intersight.server.memory.sum".to_string(), 400.0),
+                ("intersight.server.memory.mean".to_string(), 200.0),
+                ("intersight.server.memory.max".to_string(), 300.0),
+                ("intersight.server.memory.count".to_string(), 2.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_stats_aggregator_grouped() {
+        let agg = StatsAggregator::new(
+            "m".to_string(),
+            StatsOptions {
+                field: "$.NumCpus".to_string(),
+                operations: vec!["sum".into()],
+                group_by: Some("$.Model".to_string()),
+                group_by_attribute: Some("model".to_string()),
+            },
+        )
+        .unwrap();
+
+        let batch = agg.aggregate(json!({"Results": [
+            {"Model": "B200", "NumCpus": 2},
+            {"Model": "C240", "NumCpus": 1},
+            {"Model": "B200", "NumCpus": 2},
+        ]}));
+
+        assert_eq!(batch.len(), 2);
+        assert_eq!(
+            attributes(&batch[0]),
+            vec![("model".to_string(), "B200".to_string())]
+        );
+        assert_eq!(metric_values(&batch[0]), vec![("m.sum".to_string(), 4.0)]);
+        assert_eq!(
+            attributes(&batch[1]),
+            vec![("model".to_string(), "C240".to_string())]
+        );
+        assert_eq!(metric_values(&batch[1]), vec![("m.sum".to_string(), 1.0)]);
+
+        // Objects without the group are skipped, and groups without a value
+        // produce no resource
+        let agg = StatsAggregator::new(
+            "m".to_string(),
+            StatsOptions {
+                field: "$.NumCpus".to_string(),
+                operations: vec!["max".into()],
+                group_by: Some("$.Model".to_string()),
+                group_by_attribute: Some("model".to_string()),
+            },
+        )
+        .unwrap();
+        let batch = agg.aggregate(json!({"Results": [
+            {"Model": "B200", "NumCpus": 2},
+            {"NumCpus": 8},
+            {"Model": "C240"},
+        ]}));
+        assert_eq!(batch.len(), 1);
+        assert_eq!(
+            attributes(&batch[0]),
+            vec![("model".to_string(), "B200".to_string())]
+        );
+        assert_eq!(metric_values(&batch[0]), vec![("m.max".to_string(), 2.0)]);
+    }
+
+    fn state_options(mode: StateMode) -> StateOptions {
+        StateOptions {
+            field: "$.OperState".to_string(),
+            mode: Some(mode),
+            states: Some(vec!["ok".into(), "down".into()]),
+            state_attribute: Some("state".to_string()),
+            value_map: None,
+            attributes: None,
+        }
+    }
+
+    fn state_results() -> Value {
+        json!({"Results": [
+            {"Moid": "a", "OperState": "ok"},
+            {"Moid": "b", "OperState": "ok"},
+            {"Moid": "c", "OperState": "degraded"},
+            {"Moid": "d"},
+        ]})
+    }
+
+    #[test]
+    fn test_state_aggregator_count() {
+        let agg = StateAggregator::new("m".to_string(), state_options(StateMode::Count)).unwrap();
+
+        let batch = agg.aggregate(state_results());
+
+        let counts: Vec<(Vec<(String, String)>, f64)> = batch
+            .iter()
+            .map(|rm| (attributes(rm), rm.metrics[0].value))
+            .collect();
+        assert_eq!(
+            counts,
+            vec![
+                (vec![("state".to_string(), "degraded".to_string())], 1.0),
+                (vec![("state".to_string(), "down".to_string())], 0.0),
+                (vec![("state".to_string(), "ok".to_string())], 2.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_state_aggregator_state_set() {
+        let mut options = state_options(StateMode::StateSet);
+        options.attributes = Some(HashMap::from([(
+            "$.Moid".to_string(),
+            "host.id".to_string(),
+        )]));
+        let agg = StateAggregator::new("m".to_string(), options).unwrap();
+
+        let batch = agg.aggregate(state_results());
+
+        let values: Vec<(Vec<(String, String)>, f64)> = batch
+            .iter()
+            .map(|rm| (attributes(rm), rm.metrics[0].value))
+            .collect();
+        let point = |moid: &str, state: &str, value: f64| {
+            (
+                vec![
+                    ("host.id".to_string(), moid.to_string()),
+                    ("state".to_string(), state.to_string()),
+                ],
+                value,
+            )
+        };
+        assert_eq!(
+            values,
+            vec![
+                point("a", "ok", 1.0),
+                point("a", "down", 0.0),
+                point("b", "ok", 1.0),
+                point("b", "down", 0.0),
+                point("c", "ok", 0.0),
+                point("c", "down", 0.0),
+                point("c", "degraded", 1.0),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_state_aggregator_enum() {
+        let mut options = state_options(StateMode::Enum);
+        options.value_map = Some(HashMap::from([
+            ("ok".to_string(), 0.0),
+            ("degraded".to_string(), 2.0),
+        ]));
+        let agg = StateAggregator::new("m".to_string(), options).unwrap();
+
+        let batch = agg.aggregate(state_results());
+
+        let values: Vec<f64> = batch.iter().map(|rm| rm.metrics[0].value).collect();
+        assert_eq!(values, vec![0.0, 0.0, 2.0]);
+    }
+
+    #[test]
+    fn test_state_aggregator_invalid_options() {
+        assert!(StateAggregator::new("m".to_string(), state_options(StateMode::Enum)).is_err());
+
+        let mut options = state_options(StateMode::Count);
+        options.attributes = Some(HashMap::new());
+        assert!(StateAggregator::new("m".to_string(), options).is_err());
+    }
+
+    fn age_results() -> Value {
+        let now = Utc::now();
+        let time = |offset: i64| (now + chrono::Duration::seconds(offset)).to_rfc3339();
+        json!({"Results": [
+            {"Moid": "a", "EndDate": time(-100)},
+            {"Moid": "b", "EndDate": time(-3600)},
+            {"Moid": "c", "EndDate": "0001-01-01T00:00:00Z"},
+            {"Moid": "d", "EndDate": "not a date"},
+            {"Moid": "e"},
+        ]})
+    }
+
+    fn age_options(direction: AgeDirection, reduce: AgeReduce) -> TimestampAgeOptions {
+        TimestampAgeOptions {
+            field: "$.EndDate".to_string(),
+            direction: Some(direction),
+            reduce: Some(reduce),
+            attributes: None,
+        }
+    }
+
+    fn assert_near(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 5.0,
+            "expected about {}, got {}",
+            expected,
+            actual
+        );
+    }
+
+    #[test]
+    fn test_timestamp_age_aggregator_per_object() {
+        let mut options = age_options(AgeDirection::Since, AgeReduce::None);
+        options.attributes = Some(HashMap::from([(
+            "$.Moid".to_string(),
+            "host.id".to_string(),
+        )]));
+        let agg = TimestampAgeAggregator::new("m".to_string(), options).unwrap();
+
+        let batch = agg.aggregate(age_results());
+
+        assert_eq!(batch.len(), 2);
+        assert_eq!(
+            attributes(&batch[0]),
+            vec![("host.id".to_string(), "a".to_string())]
+        );
+        assert_near(batch[0].metrics[0].value, 100.0);
+        assert_near(batch[1].metrics[0].value, 3600.0);
+    }
+
+    #[test]
+    fn test_timestamp_age_aggregator_reduce() {
+        let agg = TimestampAgeAggregator::new(
+            "m".to_string(),
+            age_options(AgeDirection::Until, AgeReduce::Min),
+        )
+        .unwrap();
+
+        let batch = agg.aggregate(age_results());
+
+        assert_eq!(batch.len(), 1);
+        assert!(batch[0].attributes.is_empty());
+        assert_near(batch[0].metrics[0].value, -3600.0);
+
+        let agg = TimestampAgeAggregator::new(
+            "m".to_string(),
+            age_options(AgeDirection::Since, AgeReduce::Max),
+        )
+        .unwrap();
+        assert!(agg.aggregate(json!({"Results": []})).is_empty());
+    }
+
+    #[test]
+    fn test_timestamp_age_aggregator_attributes_require_no_reduce() {
+        let mut options = age_options(AgeDirection::Since, AgeReduce::Max);
+        options.attributes = Some(HashMap::new());
+        assert!(TimestampAgeAggregator::new("m".to_string(), options).is_err());
+    }
+
+    #[test]
+    fn test_paged_query() {
+        assert_eq!(
+            paged_query("api/v1/compute/PhysicalSummaries", 100, 0),
+            "api/v1/compute/PhysicalSummaries?$top=100&$skip=0"
+        );
+        assert_eq!(
+            paged_query("api/v1/compute/PhysicalSummaries?$select=Moid", 100, 200),
+            "api/v1/compute/PhysicalSummaries?$select=Moid&$top=100&$skip=200"
+        );
+    }
+
+    #[test]
+    fn test_info_aggregator() {
+        let agg = InfoAggregator::new(
+            "intersight.server".to_string(),
+            InfoOptions {
+                attributes: HashMap::from([
+                    ("$.Model".to_string(), "host.model".to_string()),
+                    ("$.Serial".to_string(), "host.serial".to_string()),
+                ]),
+            },
+        )
+        .unwrap();
+
+        let batch = agg.aggregate(json!({"Results": [
+            {"Model": "UCSB-B200-M5", "Serial": "FCH1"},
+            {"Model": "UCSC-C240-M5"},
+        ]}));
+
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[0].metrics[0].name, "intersight.server_info");
+        assert_eq!(batch[0].metrics[0].value, 1.0);
+        assert_eq!(
+            attributes(&batch[0]),
+            vec![
+                ("host.model".to_string(), "UCSB-B200-M5".to_string()),
+                ("host.serial".to_string(), "FCH1".to_string()),
+            ]
+        );
+        assert_eq!(
+            attributes(&batch[1]),
+            vec![("host.model".to_string(), "UCSC-C240-M5".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_info_aggregator_name() {
+        let options = || InfoOptions {
+            attributes: HashMap::from([("$.Moid".to_string(), "host.id".to_string())]),
+        };
+        let agg = InfoAggregator::new("server_info".to_string(), options()).unwrap();
+        assert_eq!(agg.name, "server_info");
+        assert!(InfoAggregator::new(
+            "server".to_string(),
+            InfoOptions {
+                attributes: HashMap::new()
+            }
+        )
+        .is_err());
+    }
+
+    fn jq(filter: &str) -> Result<JqAggregator> {
+        JqAggregator::new(
+            "m".to_string(),
+            JqOptions {
+                filter: filter.to_string(),
+            },
+        )
+    }
+
+    #[test]
+    fn test_jq_aggregator() {
+        let agg = jq(r#"[.Results[] | .Nodes[] | {name: "hx.node.disks", value: .Disks, attributes: {"hx.node": .Name, "hx.cluster": null}}]"#)
+            .unwrap();
+
+        let batch = agg.aggregate(json!({"Results": [
+            {"Nodes": [{"Name": "n1", "Disks": 4}, {"Name": "n2", "Disks": "6"}]},
+            {"Nodes": [{"Name": "n3"}]},
+        ]}));
+
+        assert_eq!(batch.len(), 2);
+        assert_eq!(batch[0].metrics[0].name, "hx.node.disks");
+        assert_eq!(batch[0].metrics[0].value, 4.0);
+        assert_eq!(
+            attributes(&batch[0]),
+            vec![("hx.node".to_string(), "n1".to_string())]
+        );
+        assert_eq!(batch[1].metrics[0].value, 6.0);
+    }
+
+    #[test]
+    fn test_jq_aggregator_stream_and_default_name() {
+        let agg = jq(".Results[] | {value: .Count}").unwrap();
+
+        let batch = agg.aggregate(json!({"Results": [{"Count": 1}, {"Count": 2}]}));
+
+        let values: Vec<(String, f64)> = batch.iter().flat_map(metric_values).collect();
+        assert_eq!(values, vec![("m".to_string(), 1.0), ("m".to_string(), 2.0)]);
+    }
+
+    #[test]
+    fn test_jq_aggregator_runtime_error() {
+        let agg = jq(".Results | error(\"boom\")").unwrap();
+        assert!(agg.aggregate(json!({"Results": []})).is_empty());
+    }
+
+    #[test]
+    fn test_jq_aggregator_invalid_filter() {
+        let err = jq("[.Results[] | {value: .Count}").err().unwrap();
+        assert!(
+            err.to_string().starts_with("invalid jq filter: expected"),
+            "{}",
+            err
+        );
+
+        let err = jq(".Results | nonexistent").err().unwrap();
+        assert_eq!(
+            err.to_string(),
+            "invalid jq filter: undefined filter 'nonexistent'"
+        );
+    }
+
+    #[test]
+    fn test_multi_aggregator() {
+        let metric =
+            |name: &str, unit: Option<&str>, attributes: Option<HashMap<String, String>>| {
+                PollerMetricConfig {
+                    name: name.to_string(),
+                    aggregator: String::new(),
+                    aggregator_options: None,
+                    unit: unit.map(str::to_string),
+                    description: None,
+                    otel_attributes: attributes,
+                    kind: None,
+                    temporality: None,
+                    monotonic: None,
+                }
+            };
+        let agg = MultiAggregator::new(vec![
+            MetricAggregator::new(
+                Box::new(ResultCountingAggregator::new("servers".to_string())),
+                &metric("servers", None, None),
+                MetricKind::Sum {
+                    cumulative: false,
+                    monotonic: false,
+                },
+                60,
+            ),
+            MetricAggregator::new(
+                Box::new(
+                    StatsAggregator::new(
+                        "memory".to_string(),
+                        StatsOptions {
+                            field: "$.TotalMemory".to_string(),
+                            operations: vec!["sum".into()],
+                            group_by: None,
+                            group_by_attribute: None,
+                        },
+                    )
+                    .unwrap(),
+                ),
+                &metric(
+                    "memory",
+                    Some("MiBy"),
+                    Some(HashMap::from([("scope".to_string(), "fleet".to_string())])),
+                ),
+                MetricKind::Gauge,
+                60,
+            ),
+        ]);
+
+        let batch =
+            agg.aggregate(json!({"Results": [{"TotalMemory": 1024}, {"TotalMemory": 2048}]}));
+
+        assert_eq!(batch.len(), 2);
+        assert_eq!(metric_values(&batch[0]), vec![("servers".to_string(), 2.0)]);
+        assert_eq!(batch[0].metrics[0].unit, "");
+        // Delta sums cover the poll interval
+        let servers = &batch[0].metrics[0];
+        assert!(matches!(servers.kind, MetricKind::Sum { .. }));
+        assert_eq!(
+            servers.time.duration_since(servers.start_time).unwrap(),
+            std::time::Duration::from_secs(60)
+        );
+        assert_eq!(batch[1].metrics[0].kind, MetricKind::Gauge);
+        assert_eq!(
+            metric_values(&batch[1]),
+            vec![("memory.sum".to_string(), 3072.0)]
+        );
+        assert_eq!(batch[1].metrics[0].unit, "MiBy");
+        assert_eq!(
+            attributes(&batch[1]),
+            vec![("scope".to_string(), "fleet".to_string())]
+        );
+    }
+}
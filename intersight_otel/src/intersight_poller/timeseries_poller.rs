@@ -1,120 +1,102 @@
 use std::collections::BTreeMap;
 
-use crate::{config::TSPollerConfig, intersight_poller::IntersightResourceMetrics};
+use crate::coalescing_client::CoalescingClient;
+use crate::config::{FieldConfig, TSPollerConfig, TSQueryType};
+use crate::intersight_poller::IntersightResourceMetrics;
 
-use super::{IntersightMetric, IntersightMetricBatch};
-use anyhow::Result;
+use super::{IntersightMetric, IntersightMetricBatch, MetricKind};
+use anyhow::{anyhow, Context, Result};
 use chrono::{prelude::*, Duration};
-use intersight_api::Client;
 use opentelemetry_proto::tonic::common::v1::{any_value, AnyValue, KeyValue};
-use serde_json::{json, Value};
+use serde_json::{json, Map, Value};
 
-pub async fn poll(client: &Client, config: &TSPollerConfig) -> Result<IntersightMetricBatch> {
-    let now = Utc::now();
-    let body = json!(
-        {
-            "queryType": "groupBy",
-            "dataSource": config.datasource,
-            "dimensions": config.dimensions,
-            "filter": config.filter,
-            "granularity": "all",
-            "intervals": [ get_interval(now) ],
-            "aggregations": config.aggregations,
-            "postAggregations": config.post_aggregations,
+/// Queries Druid for the given window and returns one data point per field
+/// name, result row and granularity bucket
+pub async fn poll_window(
+    client: &CoalescingClient,
+    config: &TSPollerConfig,
+    window: QueryWindow,
+) -> Result<IntersightMetricBatch> {
+    let (query_type, body) = match query_template(config)? {
+        Some(template) => {
+            let body = render_query(&template, window)?;
+            (template_query_type(&body)?, body)
         }
-    );
-    let (start_time, end_time) = get_interval_times(now);
+        None => {
+            let query_type = config
+                .query_type()
+                .ok_or_else(|| anyhow!("Invalid query_type {}", config.query_type_name()))?;
+            let granularity = config.granularity.clone().unwrap_or_else(|| json!("all"));
+            (
+                query_type,
+                query_body(config, query_type, window, granularity),
+            )
+        }
+    };
+    // Scan rows are individual events rather than granularity buckets
+    let bucket_duration = match query_type {
+        TSQueryType::Scan => None,
+        _ => granularity_duration(&body["granularity"]),
+    };
 
-    let response = client.post("api/v1/telemetry/GroupBys", body).await?;
+    let response = client.post(query_type.endpoint(), body).await?;
 
     info!("processing timeseries response: {}", response);
 
     let mut ret: IntersightMetricBatch = vec![];
 
-    if let Value::Array(results) = response {
+    if let Value::Array(results) = &response {
         if results.is_empty() {
             warn!(
                 "Druid returned 0 results for interval {}",
-                get_interval(now)
+                window.interval()
             );
         }
-        for result in results {
-            info!("processing timeseries result: {}", result);
+        for (timestamp, event) in result_rows(query_type, results) {
             let mut resource_metrics = IntersightResourceMetrics::default();
+            let (start_time, end_time) = window.bucket_times(&timestamp, bucket_duration);
 
-            if let Value::Object(event) = &result["event"] {
-                let attributes: BTreeMap<String, String> = BTreeMap::new();
-                if let Some(otel_dimension_to_attribute_map) =
-                    &config.otel_dimension_to_attribute_map
-                {
-                    for (dimension_name, attribute_name) in otel_dimension_to_attribute_map {
-                        if let Some(v) = event.get(dimension_name) {
-                            // attributes.insert(attribute_name.clone(), v.to_string());
-                            resource_metrics.attributes.push(KeyValue {
-                                key: attribute_name.clone(),
-                                value: Some(AnyValue {
-                                    value: Some(any_value::Value::StringValue(
-                                        v.as_str().map(String::from).unwrap_or_else(|| v.to_string()),
-                                    )),
-                                }),
-                            })
-                        }
+            let attributes: BTreeMap<String, String> = BTreeMap::new();
+            if let Some(otel_dimension_to_attribute_map) = &config.otel_dimension_to_attribute_map {
+                for (dimension_name, attribute_name) in otel_dimension_to_attribute_map {
+                    if let Some(v) = event.get(dimension_name) {
+                        // attributes.insert(attribute_name.clone(), v.to_string());
+                        resource_metrics.attributes.push(KeyValue {
+                            key: attribute_name.clone(),
+                            value: Some(AnyValue {
+                                value: Some(any_value::Value::StringValue(
+                                    v.as_str()
+                                        .map(String::from)
+                                        .unwrap_or_else(|| v.to_string()),
+                                )),
+                            }),
+                        })
                     }
                 }
+            }
 
-                for field_name in config.field_names.as_slice() {
-                    let f64value = match event.get(field_name) {
-                        None => {
-                            warn!("Field '{}' not found in Druid event, skipping", field_name);
-                            continue;
-                        }
-                        Some(Value::Null) => {
-                            debug!(
-                                "Field '{}' is null in Druid result (possible division-by-zero \
-                                in post-aggregation), skipping",
-                                field_name
-                            );
-                            continue;
-                        }
-                        Some(Value::Number(n)) => {
-                            if let Some(v) = n.as_f64() {
-                                v
-                            } else if let Some(v) = n.as_i64() {
-                                v as f64
-                            } else {
-                                warn!(
-                                    "Field '{}' has unsupported numeric type, skipping",
-                                    field_name
-                                );
-                                continue;
-                            }
-                        }
-                        Some(other) => {
-                            warn!(
-                                "Field '{}' has unexpected non-numeric type in Druid result, skipping: {}",
-                                field_name, other
-                            );
-                            continue;
-                        }
-                    };
-
-                    let mut metric = IntersightMetric::new(
-                        field_name,
-                        f64value,
-                        Some(attributes.clone()),
-                        start_time.into(),
-                        end_time.into(),
-                    );
-
-                    metric.timestamp_offset = 15 * 60;
-
-                    resource_metrics.metrics.push(metric);
-                }
+            for field in &config.field_names {
+                let Some((value, kind)) = field_metric(config.field_kind(field), field, event)
+                else {
+                    continue;
+                };
+
+                let mut metric = IntersightMetric::new(
+                    &field.name,
+                    value,
+                    Some(attributes.clone()),
+                    start_time.into(),
+                    end_time.into(),
+                );
+
+                metric.kind = kind;
+                metric.unit = field.unit.clone().unwrap_or_default();
+                metric.description = field.description.clone().unwrap_or_default();
 
-                ret.push(resource_metrics);
-            } else {
-                warn!("Druid result has no 'event' object, skipping: {}", result);
+                resource_metrics.metrics.push(metric);
             }
+
+            ret.push(resource_metrics);
         }
     } else {
         warn!(
@@ -126,41 +108,550 @@ pub async fn poll(client: &Client, config: &TSPollerConfig) -> Result<Intersight
     Ok(ret)
 }
 
-fn get_interval(now: DateTime<Utc>) -> String {
-    let (start_time, end_time) = get_interval_times(now);
+/// The Druid query for the given window
+fn query_body(
+    config: &TSPollerConfig,
+    query_type: TSQueryType,
+    window: QueryWindow,
+    granularity: Value,
+) -> Value {
+    match query_type {
+        TSQueryType::GroupBy => json!(
+            {
+                "queryType": "groupBy",
+                "dataSource": config.datasource,
+                "dimensions": config.dimensions,
+                "filter": config.filter,
+                "granularity": granularity,
+                "intervals": [ window.interval() ],
+                "aggregations": config.aggregations,
+                "postAggregations": config.post_aggregations,
+            }
+        ),
+        TSQueryType::Timeseries => json!(
+            {
+                "queryType": "timeseries",
+                "dataSource": config.datasource,
+                "filter": config.filter,
+                "granularity": granularity,
+                "intervals": [ window.interval() ],
+                "aggregations": config.aggregations,
+                "postAggregations": config.post_aggregations,
+            }
+        ),
+        TSQueryType::TopN => json!(
+            {
+                "queryType": "topN",
+                "dataSource": config.datasource,
+                "dimension": config.dimensions.first(),
+                "metric": config.metric,
+                "threshold": config.threshold,
+                "filter": config.filter,
+                "granularity": granularity,
+                "intervals": [ window.interval() ],
+                "aggregations": config.aggregations,
+                "postAggregations": config.post_aggregations,
+            }
+        ),
+        TSQueryType::Scan => {
+            let mut columns = vec!["__time".to_string()];
+            columns.extend(config.dimensions.iter().cloned());
+            columns.extend(config.field_names.iter().map(|field| field.name.clone()));
+            let mut body = json!(
+                {
+                    "queryType": "scan",
+                    "dataSource": config.datasource,
+                    "columns": columns,
+                    "filter": config.filter,
+                    "intervals": [ window.interval() ],
+                    "resultFormat": "list",
+                }
+            );
+            if let Some(limit) = config.limit {
+                body["limit"] = json!(limit);
+            }
+            body
+        }
+    }
+}
+
+/// The raw Druid query template of a timeseries poller, from `query` or the
+/// file named by `query_file`, if it has one
+pub fn query_template(config: &TSPollerConfig) -> Result<Option<String>> {
+    match (&config.query, &config.query_file) {
+        (Some(query), _) => Ok(Some(query.clone())),
+        (None, Some(path)) => std::fs::read_to_string(path)
+            .map(Some)
+            .with_context(|| format!("Unable to read query_file {}", path)),
+        (None, None) => Ok(None),
+    }
+}
+
+/// Parses a query template and fills the `{{interval}}`, `{{start}}` and
+/// `{{end}}` placeholders for the given window. Placeholders are only replaced
+/// inside JSON strings, so the template is valid JSON before rendering and the
+/// values never need escaping.
+pub fn render_query(template: &str, window: QueryWindow) -> Result<Value> {
+    let mut query: Value =
+        serde_json::from_str(template).context("query template is not valid JSON")?;
+    fill_placeholders(&mut query, window);
+    Ok(query)
+}
+
+fn fill_placeholders(value: &mut Value, window: QueryWindow) {
+    match value {
+        Value::String(s) => {
+            *s = s
+                .replace("{{interval}}", &window.interval())
+                .replace("{{start}}", &window.start.to_rfc3339())
+                .replace("{{end}}", &window.end.to_rfc3339());
+        }
+        Value::Array(values) => {
+            for value in values {
+                fill_placeholders(value, window);
+            }
+        }
+        Value::Object(map) => {
+            for value in map.values_mut() {
+                fill_placeholders(value, window);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The query type of a rendered query template, from its `queryType`
+pub fn template_query_type(query: &Value) -> Result<TSQueryType> {
+    match query["queryType"].as_str() {
+        Some(name) => TSQueryType::from_name(name).ok_or_else(|| {
+            anyhow!(
+                "unsupported queryType {} in query template, expected groupBy, timeseries, \
+                 topN or scan",
+                name
+            )
+        }),
+        None => Err(anyhow!("query template has no queryType")),
+    }
+}
+
+/// The rows of a Druid response, each with its timestamp and the object
+/// holding its dimensions and fields. The shape of the response depends on
+/// the query type:
+///
+/// - groupBy: `[{"timestamp": ..., "event": {...}}]`
+/// - timeseries: `[{"timestamp": ..., "result": {...}}]`
+/// - topN: `[{"timestamp": ..., "result": [{...}, ...]}]`
+/// - scan: `[{"segmentId": ..., "events": [{"__time": <ms>, ...}, ...]}]`
+fn result_rows(query_type: TSQueryType, results: &[Value]) -> Vec<(Value, &Map<String, Value>)> {
+    let mut rows = vec![];
+    for result in results {
+        info!("processing timeseries result: {}", result);
+        match query_type {
+            TSQueryType::GroupBy | TSQueryType::Timeseries => {
+                let key = match query_type {
+                    TSQueryType::GroupBy => "event",
+                    _ => "result",
+                };
+                match &result[key] {
+                    Value::Object(event) => rows.push((result["timestamp"].clone(), event)),
+                    _ => warn!("Druid result has no '{}' object, skipping: {}", key, result),
+                }
+            }
+            TSQueryType::TopN => match &result["result"] {
+                Value::Array(items) => rows.extend(
+                    items
+                        .iter()
+                        .filter_map(Value::as_object)
+                        .map(|item| (result["timestamp"].clone(), item)),
+                ),
+                _ => warn!("Druid result has no 'result' array, skipping: {}", result),
+            },
+            TSQueryType::Scan => match &result["events"] {
+                Value::Array(events) => {
+                    for event in events.iter().filter_map(Value::as_object) {
+                        // Scan rows have the event time in milliseconds
+                        let timestamp = event
+                            .get("__time")
+                            .and_then(Value::as_i64)
+                            .and_then(DateTime::from_timestamp_millis)
+                            .map(|t| json!(t.to_rfc3339()))
+                            .unwrap_or(Value::Null);
+                        rows.push((timestamp, event));
+                    }
+                }
+                _ => warn!("Druid result has no 'events' array, skipping: {}", result),
+            },
+        }
+    }
+    rows
+}
+
+/// The value and kind of the metric for a field of a Druid result row, `None`
+/// (after logging why) if the row has no usable value for the field.
+/// Histogram fields hold the bucket counts and summary fields the values at
+/// the configured quantiles, as returned by Druid's sketch post-aggregations.
+fn field_metric(
+    kind: &str,
+    field: &FieldConfig,
+    event: &Map<String, Value>,
+) -> Option<(f64, MetricKind)> {
+    let optional_number =
+        |name: &Option<String>| name.as_ref().and_then(|name| number(name, event.get(name)));
+
+    match kind {
+        "histogram" => {
+            let bounds = field.bounds.clone().unwrap_or_default();
+            let bucket_counts: Vec<u64> = numbers(&field.name, event.get(&field.name))?
+                .into_iter()
+                .map(|count| count.round() as u64)
+                .collect();
+            if bucket_counts.len() != bounds.len() + 1 {
+                warn!(
+                    "Field '{}' has {} bucket counts for {} bounds, skipping",
+                    field.name,
+                    bucket_counts.len(),
+                    bounds.len()
+                );
+                return None;
+            }
+            let count = bucket_counts.iter().sum::<u64>() as f64;
+            let sum = optional_number(&field.sum_field);
+            Some((
+                count,
+                MetricKind::Histogram {
+                    bounds,
+                    bucket_counts,
+                    sum,
+                },
+            ))
+        }
+        "summary" => {
+            let quantiles = field.quantiles.clone().unwrap_or_default();
+            let values = numbers(&field.name, event.get(&field.name))?;
+            if values.len() != quantiles.len() {
+                warn!(
+                    "Field '{}' has {} values for {} quantiles, skipping",
+                    field.name,
+                    values.len(),
+                    quantiles.len()
+                );
+                return None;
+            }
+            // A summary without its count and sum would claim zero observations
+            let (Some(count), Some(sum)) = (
+                optional_number(&field.count_field),
+                optional_number(&field.sum_field),
+            ) else {
+                warn!(
+                    "Field '{}' has no count_field or sum_field value, skipping",
+                    field.name
+                );
+                return None;
+            };
+            Some((
+                count,
+                MetricKind::Summary {
+                    quantiles: quantiles.into_iter().zip(values).collect(),
+                    sum,
+                },
+            ))
+        }
+        kind => {
+            let kind =
+                match MetricKind::scalar(Some(kind), field.temporality.as_deref(), field.monotonic)
+                {
+                    Ok(kind) => kind,
+                    Err(err) => {
+                        warn!("Field '{}': {}, skipping", field.name, err);
+                        return None;
+                    }
+                };
+            Some((number(&field.name, event.get(&field.name))?, kind))
+        }
+    }
+}
+
+/// The numeric value of a field of a Druid result row
+fn number(field_name: &str, value: Option<&Value>) -> Option<f64> {
+    match value {
+        None => {
+            warn!("Field '{}' not found in Druid event, skipping", field_name);
+            None
+        }
+        Some(Value::Null) => {
+            debug!(
+                "Field '{}' is null in Druid result (possible division-by-zero \
+                in post-aggregation), skipping",
+                field_name
+            );
+            None
+        }
+        Some(Value::Number(n)) => {
+            let v = n.as_f64().or_else(|| n.as_i64().map(|v| v as f64));
+            if v.is_none() {
+                warn!(
+                    "Field '{}' has unsupported numeric type, skipping",
+                    field_name
+                );
+            }
+            v
+        }
+        Some(other) => {
+            warn!(
+                "Field '{}' has unexpected non-numeric type in Druid result, skipping: {}",
+                field_name, other
+            );
+            None
+        }
+    }
+}
+
+/// The numeric values of an array field of a Druid result row
+fn numbers(field_name: &str, value: Option<&Value>) -> Option<Vec<f64>> {
+    match value {
+        Some(Value::Array(items)) => items
+            .iter()
+            .map(|item| number(field_name, Some(item)))
+            .collect(),
+        None => {
+            warn!("Field '{}' not found in Druid event, skipping", field_name);
+            None
+        }
+        Some(other) => {
+            warn!(
+                "Field '{}' is not an array in Druid result, skipping: {}",
+                field_name, other
+            );
+            None
+        }
+    }
+}
+
+/// Queries Druid's TimeBoundary for the time of the latest data in a datasource
+pub async fn latest_data_time(
+    client: &CoalescingClient,
+    datasource: &str,
+) -> Result<DateTime<Utc>> {
+    let body = json!(
+        {
+            "queryType": "timeBoundary",
+            "dataSource": datasource,
+            "bound": "maxTime",
+        }
+    );
+    let response = client.post("api/v1/telemetry/TimeBoundaries", body).await?;
+    parse_max_time(&response)
+        .ok_or_else(|| anyhow!("unexpected TimeBoundary response: {}", response))
+}
+
+fn parse_max_time(response: &Value) -> Option<DateTime<Utc>> {
+    let result = match response {
+        Value::Array(results) => &results.first()?["result"],
+        other => &other["result"],
+    };
+    let max_time = DateTime::parse_from_rfc3339(result["maxTime"].as_str()?).ok()?;
+    Some(max_time.with_timezone(&Utc))
+}
+
+/// The `intersight.telemetry.freshness_lag` metric: seconds between `now` and
+/// the latest data in the datasource. Labelled with the tspoller as well as the
+/// datasource, since several tspollers can query the same datasource.
+pub fn freshness_metrics(
+    tspoller: &str,
+    datasource: &str,
+    max_time: DateTime<Utc>,
+    now: DateTime<Utc>,
+) -> IntersightResourceMetrics {
+    let lag = (now - max_time).num_milliseconds() as f64 / 1000.0;
+    let mut metric = IntersightMetric::new(
+        "intersight.telemetry.freshness_lag",
+        lag,
+        None,
+        now.into(),
+        now.into(),
+    );
+    metric.unit = "s".to_string();
+
+    IntersightResourceMetrics {
+        attributes: vec![
+            KeyValue {
+                key: "datasource".to_string(),
+                value: Some(AnyValue {
+                    value: Some(any_value::Value::StringValue(datasource.to_string())),
+                }),
+            },
+            KeyValue {
+                key: "tspoller".to_string(),
+                value: Some(AnyValue {
+                    value: Some(any_value::Value::StringValue(tspoller.to_string())),
+                }),
+            },
+        ],
+        metrics: vec![metric],
+        ..Default::default()
+    }
+}
+
+/// A window of Druid data queried by a timeseries poller
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct QueryWindow {
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+}
+
+impl QueryWindow {
+    /// The most recent `window` seconds long window that ended at least `lag`
+    /// seconds before `now`, with its end aligned to a multiple of `alignment`
+    /// seconds. This is to ensure that all the data is complete in the Druid
+    /// results.
+    pub fn latest(now: DateTime<Utc>, lag: u64, window: u64, alignment: u64) -> QueryWindow {
+        let end = (now - Duration::seconds(lag as i64)).timestamp();
+        let end = end - end.rem_euclid(alignment.max(1) as i64);
+        let end = DateTime::from_timestamp(end, 0).expect("window end out of range");
+
+        QueryWindow {
+            start: end - Duration::seconds(window as i64),
+            end,
+        }
+    }
+
+    /// All windows that lie within `from` and `to`, in order
+    pub fn range(
+        from: DateTime<Utc>,
+        to: DateTime<Utc>,
+        window: u64,
+        alignment: u64,
+    ) -> Vec<QueryWindow> {
+        let latest = QueryWindow::latest(to, 0, window, alignment);
+        // The last window that starts before `from`
+        let before = QueryWindow::latest(
+            from + Duration::seconds(window as i64 - 1),
+            0,
+            window,
+            alignment,
+        );
+        QueryWindow::pending(Some(before), latest, alignment)
+    }
+
+    /// The windows after `last` up to and including `latest`, in order, each
+    /// ending `alignment` seconds after the previous one. Only `latest` if
+    /// nothing has been exported yet.
+    pub fn pending(
+        last: Option<QueryWindow>,
+        latest: QueryWindow,
+        alignment: u64,
+    ) -> Vec<QueryWindow> {
+        let Some(last) = last else {
+            return vec![latest];
+        };
 
-    let end = end_time
-        .with_second(0)
-        .unwrap()
-        .with_nanosecond(0)
-        .unwrap()
-        .to_rfc3339();
-    let start = start_time
-        .with_second(0)
-        .unwrap()
-        .with_nanosecond(0)
-        .unwrap()
-        .to_rfc3339();
+        let length = latest.end - latest.start;
+        let step = Duration::seconds(alignment.max(1) as i64);
+        let mut windows = vec![];
+        let mut end = last.end + step;
+        while end <= latest.end {
+            windows.push(QueryWindow {
+                start: end - length,
+                end,
+            });
+            end += step;
+        }
+        windows
+    }
+
+    /// The Druid ISO 8601 interval for this window
+    pub fn interval(&self) -> String {
+        format!("{}/{}", self.start.to_rfc3339(), self.end.to_rfc3339())
+    }
 
-    format!("{start}/{end}")
+    /// Start and end time of the granularity bucket starting at `timestamp`
+    /// (the `timestamp` of a Druid result row). The end is the start of the
+    /// next bucket, or the end of the window if the bucket length is unknown.
+    fn bucket_times(
+        &self,
+        timestamp: &Value,
+        bucket_duration: Option<Duration>,
+    ) -> (DateTime<Utc>, DateTime<Utc>) {
+        let start = match timestamp
+            .as_str()
+            .and_then(|t| DateTime::parse_from_rfc3339(t).ok())
+        {
+            Some(start) => start.with_timezone(&Utc).max(self.start),
+            None => return (self.start, self.end),
+        };
+        let end = match bucket_duration {
+            Some(duration) => (start + duration).min(self.end),
+            None => self.end,
+        };
+        (start, end)
+    }
 }
 
-fn get_interval_times(now: DateTime<Utc>) -> (DateTime<Utc>, DateTime<Utc>) {
-    // The interval is always the 10 minute interval that started 25 minutes ago.
-    // This is to ensure that all the data complete in the Druid results.
-    // Start and end times are aligned to 10-minute boundaries (e.g., 01:00, 01:10, 01:20).
+/// Length of the buckets for a Druid granularity, `None` for `all` (the whole
+/// window) or granularities whose length isn't fixed
+fn granularity_duration(granularity: &Value) -> Option<Duration> {
+    match granularity {
+        Value::String(name) => match name.as_str() {
+            "second" => Some(Duration::seconds(1)),
+            "minute" => Some(Duration::minutes(1)),
+            "five_minute" => Some(Duration::minutes(5)),
+            "ten_minute" => Some(Duration::minutes(10)),
+            "fifteen_minute" => Some(Duration::minutes(15)),
+            "thirty_minute" => Some(Duration::minutes(30)),
+            "hour" => Some(Duration::hours(1)),
+            "six_hour" => Some(Duration::hours(6)),
+            "day" => Some(Duration::days(1)),
+            "week" => Some(Duration::weeks(1)),
+            _ => None,
+        },
+        Value::Object(o) => match o.get("type").and_then(Value::as_str) {
+            Some("duration") => o
+                .get("duration")
+                .and_then(Value::as_i64)
+                .map(Duration::milliseconds),
+            Some("period") => o
+                .get("period")
+                .and_then(Value::as_str)
+                .and_then(parse_period),
+            _ => None,
+        },
+        _ => None,
+    }
+}
 
-    // Align end to 10-minute boundary: go back 15 minutes and round down to nearest 10-minute boundary
-    let end_time = now + Duration::minutes(-15);
-    let end_minutes = end_time.minute() as i64;
-    let end_aligned = end_time - Duration::minutes(end_minutes % 10);
+/// Parses the fixed-length ISO 8601 periods used for Druid granularities, such
+/// as `PT5M`, `PT1H` or `P1D`
+fn parse_period(period: &str) -> Option<Duration> {
+    let (date, time) = match period.strip_prefix('P')?.split_once('T') {
+        Some((date, time)) => (date, time),
+        None => (period.strip_prefix('P')?, ""),
+    };
 
-    // Align start to 10-minute boundary: go back 25 minutes and round down to nearest 10-minute boundary
-    let start_time = now + Duration::minutes(-25);
-    let start_minutes = start_time.minute() as i64;
-    let start_aligned = start_time - Duration::minutes(start_minutes % 10);
+    let mut total = Duration::zero();
+    for (part, units) in [(date, "WD"), (time, "HMS")] {
+        let mut number = String::new();
+        for c in part.chars() {
+            if c.is_ascii_digit() {
+                number.push(c);
+                continue;
+            }
+            let n: i64 = number.parse().ok()?;
+            number.clear();
+            total += match (units, c) {
+                ("WD", 'W') => Duration::weeks(n),
+                ("WD", 'D') => Duration::days(n),
+                ("HMS", 'H') => Duration::hours(n),
+                ("HMS", 'M') => Duration::minutes(n),
+                ("HMS", 'S') => Duration::seconds(n),
+                _ => return None,
+            };
+        }
+        if !number.is_empty() {
+            return None;
+        }
+    }
 
-    (start_aligned, end_aligned)
+    (total > Duration::zero()).then_some(total)
 }
 
 #[cfg(test)]
@@ -171,7 +662,7 @@ mod tests {
     fn test_get_interval_aligns_to_10_minute_boundaries() {
         // Test with a time at 14:37:45 UTC
         let now = Utc.with_ymd_and_hms(2025, 1, 15, 14, 37, 45).unwrap();
-        let interval = get_interval(now);
+        let interval = QueryWindow::latest(now, 900, 600, 600).interval();
 
         // At 14:37, end should be 15 min ago -> 14:22, aligned to 14:20
         // At 14:37, start should be 25 min ago -> 14:12, aligned to 14:10
@@ -186,7 +677,7 @@ mod tests {
     fn test_get_interval_on_boundary() {
         // Test when current time is exactly on a boundary (14:30:00)
         let now = Utc.with_ymd_and_hms(2025, 1, 15, 14, 25, 0).unwrap();
-        let interval = get_interval(now);
+        let interval = QueryWindow::latest(now, 900, 600, 600).interval();
 
         // At 14:25, end should be 15 min ago -> 14:10, aligned to 14:10
         // At 14:25, start should be 25 min ago -> 14:00, aligned to 14:00
@@ -201,7 +692,7 @@ mod tests {
     fn test_get_interval_just_over_boundary() {
         // Test when current time is exactly on a boundary (14:30:00)
         let now = Utc.with_ymd_and_hms(2025, 1, 15, 14, 25, 1).unwrap();
-        let interval = get_interval(now);
+        let interval = QueryWindow::latest(now, 900, 600, 600).interval();
 
         // At 14:25:01, end should be 15 min ago -> 14:10:01, aligned to 14:10
         // At 14:25:01, start should be 25 min ago -> 14:00:01, aligned to 14:00
@@ -216,7 +707,7 @@ mod tests {
     fn test_get_interval_just_under_boundary() {
         // Test when current time is exactly on a boundary (14:30:00)
         let now = Utc.with_ymd_and_hms(2025, 1, 15, 14, 24, 59).unwrap();
-        let interval = get_interval(now);
+        let interval = QueryWindow::latest(now, 900, 600, 600).interval();
 
         // At 14:24:59, end should be 15 min ago -> 14:09:59, aligned to 14:00
         // At 14:24:59, start should be 25 min ago -> 13:59:59, aligned to 13:50
@@ -226,4 +717,329 @@ mod tests {
             interval
         );
     }
+
+    #[test]
+    fn test_latest_window_with_custom_lag_and_alignment() {
+        let now = Utc.with_ymd_and_hms(2025, 1, 15, 14, 37, 45).unwrap();
+
+        // 5 minute windows ending at least 5 minutes ago
+        let window = QueryWindow::latest(now, 300, 300, 300);
+        assert_eq!(
+            window.interval(),
+            "2025-01-15T14:25:00+00:00/2025-01-15T14:30:00+00:00"
+        );
+
+        // An hour of data up to the last full hour, at least 10 minutes ago
+        let window = QueryWindow::latest(now, 600, 3600, 3600);
+        assert_eq!(
+            window.interval(),
+            "2025-01-15T13:00:00+00:00/2025-01-15T14:00:00+00:00"
+        );
+    }
+
+    #[test]
+    fn test_granularity_duration() {
+        assert_eq!(granularity_duration(&json!("all")), None);
+        assert_eq!(
+            granularity_duration(&json!("five_minute")),
+            Some(Duration::minutes(5))
+        );
+        assert_eq!(
+            granularity_duration(&json!({"type": "period", "period": "PT1H30M"})),
+            Some(Duration::minutes(90))
+        );
+        assert_eq!(
+            granularity_duration(&json!({"type": "duration", "duration": 60000})),
+            Some(Duration::minutes(1))
+        );
+        assert_eq!(
+            granularity_duration(&json!({"type": "period", "period": "P1M"})),
+            None
+        );
+    }
+
+    #[test]
+    fn test_bucket_times() {
+        let now = Utc.with_ymd_and_hms(2025, 1, 15, 14, 37, 45).unwrap();
+        let window = QueryWindow::latest(now, 900, 600, 600);
+        let time = |h, m| Utc.with_ymd_and_hms(2025, 1, 15, h, m, 0).unwrap();
+
+        assert_eq!(
+            window.bucket_times(
+                &json!("2025-01-15T14:15:00.000Z"),
+                Some(Duration::minutes(5))
+            ),
+            (time(14, 15), time(14, 20))
+        );
+        assert_eq!(
+            window.bucket_times(&json!("2025-01-15T14:10:00.000Z"), None),
+            (time(14, 10), time(14, 20))
+        );
+        assert_eq!(
+            window.bucket_times(&Value::Null, None),
+            (time(14, 10), time(14, 20))
+        );
+    }
+
+    #[test]
+    fn test_pending_windows() {
+        let now = Utc.with_ymd_and_hms(2025, 1, 15, 14, 37, 45).unwrap();
+        let latest = QueryWindow::latest(now, 900, 600, 600);
+
+        assert_eq!(QueryWindow::pending(None, latest, 600), vec![latest]);
+        assert!(QueryWindow::pending(Some(latest), latest, 600).is_empty());
+
+        // Two windows were missed since 13:50-14:00 was exported
+        let last = QueryWindow::latest(now - Duration::minutes(20), 900, 600, 600);
+        let intervals: Vec<String> = QueryWindow::pending(Some(last), latest, 600)
+            .iter()
+            .map(QueryWindow::interval)
+            .collect();
+        assert_eq!(
+            intervals,
+            vec![
+                "2025-01-15T14:00:00+00:00/2025-01-15T14:10:00+00:00",
+                "2025-01-15T14:10:00+00:00/2025-01-15T14:20:00+00:00",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_parse_max_time() {
+        let response = json!([{
+            "timestamp": "2025-01-15T14:21:00.000Z",
+            "result": {"maxTime": "2025-01-15T14:21:00.000Z"}
+        }]);
+        assert_eq!(
+            parse_max_time(&response),
+            Some(Utc.with_ymd_and_hms(2025, 1, 15, 14, 21, 0).unwrap())
+        );
+        assert_eq!(parse_max_time(&json!([])), None);
+        assert_eq!(parse_max_time(&json!({"result": {}})), None);
+    }
+
+    #[test]
+    fn test_adaptive_window_and_freshness() {
+        let now = Utc.with_ymd_and_hms(2025, 1, 15, 14, 37, 45).unwrap();
+        let max_time = Utc.with_ymd_and_hms(2025, 1, 15, 14, 31, 15).unwrap();
+
+        // Data is only 6.5 minutes behind, so 14:20-14:30 is already complete
+        assert_eq!(
+            QueryWindow::latest(max_time, 0, 600, 600).interval(),
+            "2025-01-15T14:20:00+00:00/2025-01-15T14:30:00+00:00"
+        );
+
+        let rm = freshness_metrics("power", "PhysicalEntities", max_time, now);
+        assert_eq!(rm.metrics[0].value, 390.0);
+        assert_eq!(rm.metrics[0].unit, "s");
+        let keys: Vec<&str> = rm.attributes.iter().map(|kv| kv.key.as_str()).collect();
+        assert_eq!(keys, ["datasource", "tspoller"]);
+    }
+
+    #[test]
+    fn test_window_range() {
+        let time = |h, m| Utc.with_ymd_and_hms(2025, 1, 15, h, m, 0).unwrap();
+        let intervals = |from, to| {
+            QueryWindow::range(from, to, 600, 600)
+                .iter()
+                .map(|w| format!("{}-{}", w.start.format("%H:%M"), w.end.format("%H:%M")))
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            intervals(time(14, 0), time(14, 30)),
+            vec!["14:00-14:10", "14:10-14:20", "14:20-14:30"]
+        );
+        assert_eq!(
+            intervals(time(14, 5), time(14, 35)),
+            vec!["14:10-14:20", "14:20-14:30"]
+        );
+        assert!(intervals(time(14, 5), time(14, 10)).is_empty());
+    }
+
+    #[test]
+    fn test_query_type_endpoints() {
+        // Intersight's resource names, which don't follow the Druid queryType
+        // casing (`timeseries` is served by `TimeSeries`)
+        let endpoint = |name| TSQueryType::from_name(name).unwrap().endpoint();
+        assert_eq!(endpoint("groupBy"), "api/v1/telemetry/GroupBys");
+        assert_eq!(endpoint("timeseries"), "api/v1/telemetry/TimeSeries");
+        assert_eq!(endpoint("topN"), "api/v1/telemetry/TopNs");
+        assert_eq!(endpoint("scan"), "api/v1/telemetry/Scans");
+        assert_eq!(TSQueryType::from_name("search"), None);
+    }
+
+    #[test]
+    fn test_result_rows() {
+        let groupby = json!([
+            {"timestamp": "2025-01-15T14:00:00.000Z", "event": {"host": "a", "cpu": 1}},
+            {"timestamp": "2025-01-15T14:00:00.000Z"}
+        ]);
+        let rows = result_rows(TSQueryType::GroupBy, groupby.as_array().unwrap());
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].1["host"], "a");
+
+        let timeseries = json!([{"timestamp": "2025-01-15T14:00:00.000Z", "result": {"cpu": 2}}]);
+        let rows = result_rows(TSQueryType::Timeseries, timeseries.as_array().unwrap());
+        assert_eq!(rows[0].0, "2025-01-15T14:00:00.000Z");
+        assert_eq!(rows[0].1["cpu"], 2);
+
+        let topn = json!([{
+            "timestamp": "2025-01-15T14:00:00.000Z",
+            "result": [{"host": "a", "cpu": 3}, {"host": "b", "cpu": 2}]
+        }]);
+        let rows = result_rows(TSQueryType::TopN, topn.as_array().unwrap());
+        let hosts: Vec<_> = rows.iter().map(|(_, row)| &row["host"]).collect();
+        assert_eq!(hosts, ["a", "b"]);
+
+        let scan = json!([{
+            "segmentId": "s",
+            "columns": ["__time", "host", "cpu"],
+            "events": [{"__time": 1736949600000i64, "host": "a", "cpu": 4}]
+        }]);
+        let rows = result_rows(TSQueryType::Scan, scan.as_array().unwrap());
+        assert_eq!(rows[0].0, "2025-01-15T14:00:00+00:00");
+        assert_eq!(rows[0].1["cpu"], 4);
+    }
+
+    #[test]
+    fn test_query_body() {
+        let config: TSPollerConfig = serde_json::from_value(json!({
+            "name": "top_power",
+            "datasource": "PhysicalEntities",
+            "dimensions": ["host.name"],
+            "field_names": ["power"],
+            "metric": "power",
+            "threshold": 5,
+            "limit": 100,
+        }))
+        .unwrap();
+        let window = QueryWindow::latest(
+            Utc.with_ymd_and_hms(2025, 1, 15, 14, 37, 45).unwrap(),
+            900,
+            600,
+            600,
+        );
+
+        let body = query_body(&config, TSQueryType::TopN, window, json!("all"));
+        assert_eq!(body["queryType"], "topN");
+        assert_eq!(body["dimension"], "host.name");
+        assert_eq!(body["metric"], "power");
+        assert_eq!(body["threshold"], 5);
+
+        let body = query_body(&config, TSQueryType::Scan, window, json!("all"));
+        assert_eq!(body["columns"], json!(["__time", "host.name", "power"]));
+        assert_eq!(body["limit"], 100);
+        assert!(body.get("granularity").is_none());
+    }
+
+    #[test]
+    fn test_render_query() {
+        let window = QueryWindow::latest(
+            Utc.with_ymd_and_hms(2025, 1, 15, 14, 37, 45).unwrap(),
+            900,
+            600,
+            600,
+        );
+        let template = r#"{
+            "queryType": "groupBy",
+            "intervals": ["{{interval}}"],
+            "filter": {"type": "interval", "intervals": ["{{start}}/{{end}}"]},
+            "context": {"start": "{{start}}", "end": "{{end}}"}
+        }"#;
+
+        let query = render_query(template, window).unwrap();
+        assert_eq!(
+            query["intervals"][0],
+            "2025-01-15T14:10:00+00:00/2025-01-15T14:20:00+00:00"
+        );
+        assert_eq!(
+            query["filter"]["intervals"][0],
+            "2025-01-15T14:10:00+00:00/2025-01-15T14:20:00+00:00"
+        );
+        assert_eq!(query["context"]["start"], "2025-01-15T14:10:00+00:00");
+        assert_eq!(query["context"]["end"], "2025-01-15T14:20:00+00:00");
+        assert_eq!(template_query_type(&query).unwrap(), TSQueryType::GroupBy);
+
+        assert!(render_query("{\"queryType\": ", window).is_err());
+        assert!(template_query_type(&json!({"queryType": "select"})).is_err());
+        assert!(template_query_type(&json!({})).is_err());
+    }
+
+    #[test]
+    fn test_field_metric_kinds() {
+        let event = json!({
+            "ops": 42,
+            "latency_hist": [3.0, 2.0, 1.0],
+            "latency_quantiles": [12.0, 80.0],
+            "latency_count": 6,
+            "latency_sum": 250.5
+        });
+        let event = event.as_object().unwrap();
+        let field = |json: Value| -> FieldConfig { serde_json::from_value(json).unwrap() };
+
+        assert_eq!(
+            field_metric("sum", &field(json!("ops")), event),
+            Some((
+                42.0,
+                MetricKind::Sum {
+                    cumulative: false,
+                    monotonic: true
+                }
+            ))
+        );
+
+        let histogram = field(json!({
+            "name": "latency_hist",
+            "kind": "histogram",
+            "bounds": [10.0, 100.0],
+            "sum_field": "latency_sum"
+        }));
+        assert_eq!(
+            field_metric("histogram", &histogram, event),
+            Some((
+                6.0,
+                MetricKind::Histogram {
+                    bounds: vec![10.0, 100.0],
+                    bucket_counts: vec![3, 2, 1],
+                    sum: Some(250.5)
+                }
+            ))
+        );
+
+        let summary = field(json!({
+            "name": "latency_quantiles",
+            "kind": "summary",
+            "quantiles": [0.5, 0.99],
+            "count_field": "latency_count",
+            "sum_field": "latency_sum"
+        }));
+        assert_eq!(
+            field_metric("summary", &summary, event),
+            Some((
+                6.0,
+                MetricKind::Summary {
+                    quantiles: vec![(0.5, 12.0), (0.99, 80.0)],
+                    sum: 250.5
+                }
+            ))
+        );
+
+        // Summaries need both their count and sum
+        let without_count = field(json!({
+            "name": "latency_quantiles",
+            "kind": "summary",
+            "quantiles": [0.5, 0.99],
+            "sum_field": "latency_sum"
+        }));
+        assert_eq!(field_metric("summary", &without_count, event), None);
+
+        // Bucket counts must match the bounds
+        let mismatched = field(json!({
+            "name": "latency_hist",
+            "kind": "histogram",
+            "bounds": [10.0]
+        }));
+        assert_eq!(field_metric("histogram", &mismatched, event), None);
+    }
 }
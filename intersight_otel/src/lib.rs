@@ -0,0 +1,305 @@
+//! Polls the Cisco Intersight API and exports the results as OpenTelemetry
+//! metrics.
+//!
+//! The `intersight_otel` binary is a thin wrapper around [`run`]. Applications
+//! embedding this crate can register their own aggregators with
+//! [`register_aggregator`] before calling [`run`].
+
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use tokio::sync::mpsc::Sender;
+use tokio::task::{JoinHandle, JoinSet};
+
+#[macro_use]
+extern crate log;
+
+mod attribute_enricher;
+mod backfill;
+mod coalescing_client;
+pub mod config;
+mod config_check;
+mod console_exporter;
+mod discover;
+mod file_exporter;
+pub mod intersight_poller;
+mod metric_merger;
+mod prometheus_exporter;
+mod ucum;
+
+pub use intersight_poller::{register_aggregator, Aggregator};
+
+const CLIENT_STATS_INTERVAL_SECS: u64 = 600;
+
+/// Loads the configuration and runs the pollers and exporters as selected by
+/// the command line arguments. Only returns when running in `--once` mode, for
+/// the `check`, `backfill` and `discover` subcommands or on error.
+pub async fn run(args: config::Args) -> Result<()> {
+    let mut config =
+        config::GlobalConfig::new(&args.config_file).context("Unable to load config")?;
+
+    if let Some(config::Command::Check) = args.command {
+        return config_check::run(&config);
+    }
+    config.load_query_files()?;
+
+    info!(
+        "Using Intersight key_id {} and key_file {}",
+        config.key_id(),
+        config.key_file
+    );
+
+    // let key_bytes = fs::read(&config.key_file)?;
+    // let client = intersight_api::Client::from_key_bytes(config.key_id(), &key_bytes, None)?;
+
+    let mut intersight_config = intersight_api::config::Config::new()
+        .with_key_id(config.key_id())
+        .with_key_file(&config.key_file)?;
+
+    if let Some(intersight_host) = &config.intersight_host {
+        intersight_config = intersight_config.with_host(intersight_host);
+    }
+
+    if let Some(intersight_accpet_invalid_certs) = config.intersight_accept_invalid_certs {
+        intersight_config = intersight_config.with_insecure(intersight_accpet_invalid_certs)
+    }
+
+    let client = coalescing_client::CoalescingClient::new(
+        intersight_config.build_client()?,
+        config.coalesce_requests(),
+        Some(Duration::from_secs(config.response_cache_ttl())),
+    );
+    if config.coalesce_requests() || config.response_cache_ttl() > 0 {
+        start_client_stats_logger(client.clone());
+    }
+
+    if let Some(config::Command::Discover(discover_args)) = &args.command {
+        return discover::run(discover_args, &client).await;
+    }
+
+    // Start a metric merger task for each exporter; pollers send their batches
+    // to the exporters they are routed to
+    let mut exporter_configs = config.exporter_configs();
+    if args.dry_run {
+        info!("Dry-run mode: metrics will be printed to stdout instead of sent to the exporters");
+        exporter_configs = exporter_configs
+            .into_iter()
+            .map(config::ExporterConfig::dry_run)
+            .collect();
+        // Nothing is exported in dry-run mode, so no exporter needs to be configured
+        if exporter_configs.is_empty() {
+            exporter_configs.push(config::ExporterConfig::stdout("default".to_string()));
+        }
+    }
+    let (exporter_map, merge_handles) = metric_merger::start_exporters(&exporter_configs).await?;
+
+    // Build the shared enricher map once
+    let enricher_map = attribute_enricher::build_enricher_map(
+        config.enrichers.as_deref().unwrap_or_default(),
+        &client,
+    )?;
+
+    if let Some(config::Command::Backfill(backfill_args)) = &args.command {
+        return backfill::run(
+            backfill_args,
+            &client,
+            &config,
+            &enricher_map,
+            exporter_map,
+            merge_handles,
+        )
+        .await;
+    }
+
+    if args.once {
+        return run_once(&client, &config, &enricher_map, exporter_map, merge_handles).await;
+    }
+
+    let mut poller_handles: Vec<tokio::task::JoinHandle<()>> = vec![];
+
+    // Start all the pollers based on the config file(s)
+    if let Some(poller_configs) = config.pollers {
+        for poller_config in poller_configs {
+            let enrichers = attribute_enricher::resolve_enrichers(
+                poller_config.enrichers.as_deref().unwrap_or_default(),
+                &enricher_map,
+            );
+            let router = metric_merger::resolve_exporters(
+                poller_config.exporters.as_deref().unwrap_or_default(),
+                &exporter_map,
+            )?;
+            let handle = intersight_poller::start_intersight_poller(
+                router,
+                &client,
+                &poller_config,
+                enrichers,
+            )?;
+            poller_handles.push(handle);
+        }
+    }
+
+    // Start all the timeseries pollers based on the config file(s)
+    if let Some(tspoller_configs) = config.tspollers {
+        for tspoller_config in tspoller_configs {
+            let enrichers = attribute_enricher::resolve_enrichers(
+                tspoller_config.enrichers.as_deref().unwrap_or_default(),
+                &enricher_map,
+            );
+            let router = metric_merger::resolve_exporters(
+                tspoller_config.exporters.as_deref().unwrap_or_default(),
+                &exporter_map,
+            )?;
+            let handle = intersight_poller::start_intersight_tspoller(
+                router,
+                &client,
+                &tspoller_config,
+                enrichers,
+            )?;
+            poller_handles.push(handle);
+        }
+    }
+
+    // Supervise poller tasks — they should never exit. If one does (panic or bug),
+    // log loudly so the silent metric gap doesn't go unnoticed.
+    for handle in poller_handles {
+        tokio::spawn(async move {
+            match handle.await {
+                Ok(()) => {
+                    error!("Poller task exited unexpectedly — no further metrics from this poller")
+                }
+                Err(e) => error!(
+                    "Poller task panicked: {} — no further metrics from this poller",
+                    e
+                ),
+            }
+        });
+    }
+
+    // Keep running until the metric_merger tasks finish (i.e. never)
+    for merge_handle in merge_handles {
+        merge_handle.await?;
+    }
+
+    Ok(())
+}
+
+/// Runs every poller and timeseries poller exactly once, waits for the exporters
+/// to finish exporting the results and returns an error if any poller or export
+/// failed.
+async fn run_once(
+    client: &coalescing_client::CoalescingClient,
+    config: &config::GlobalConfig,
+    enricher_map: &HashMap<String, Arc<attribute_enricher::AttributeEnricher>>,
+    exporter_map: HashMap<String, Sender<metric_merger::ExportRequest>>,
+    merge_handles: Vec<JoinHandle<()>>,
+) -> Result<()> {
+    let mut tasks = JoinSet::new();
+
+    for poller_config in config.pollers.clone().unwrap_or_default() {
+        let enrichers = attribute_enricher::resolve_enrichers(
+            poller_config.enrichers.as_deref().unwrap_or_default(),
+            enricher_map,
+        );
+        let router = metric_merger::resolve_exporters(
+            poller_config.exporters.as_deref().unwrap_or_default(),
+            &exporter_map,
+        )?;
+        let client = client.clone();
+        tasks.spawn(async move {
+            let result =
+                intersight_poller::run_intersight_poller_once(&client, &poller_config, &enrichers)
+                    .await;
+            let name = format!("Poller '{}'", poller_config.name);
+            (name, send_once_result(result, &router).await)
+        });
+    }
+
+    for tspoller_config in config.tspollers.clone().unwrap_or_default() {
+        let enrichers = attribute_enricher::resolve_enrichers(
+            tspoller_config.enrichers.as_deref().unwrap_or_default(),
+            enricher_map,
+        );
+        let router = metric_merger::resolve_exporters(
+            tspoller_config.exporters.as_deref().unwrap_or_default(),
+            &exporter_map,
+        )?;
+        let client = client.clone();
+        tasks.spawn(async move {
+            let result = intersight_poller::run_intersight_tspoller_once(
+                &client,
+                &tspoller_config,
+                &enrichers,
+            )
+            .await;
+            let name = format!("TSPoller '{}'", tspoller_config.name);
+            (name, send_once_result(result, &router).await)
+        });
+    }
+
+    // The metric mergers finish once every sender has been dropped and all
+    // received batches have been exported
+    drop(exporter_map);
+
+    let mut total = 0;
+    let mut failed = 0;
+    while let Some(joined) = tasks.join_next().await {
+        total += 1;
+        match joined {
+            Ok((name, Ok(metric_count))) => info!("{}: OK ({} metrics)", name, metric_count),
+            Ok((name, Err(err))) => {
+                failed += 1;
+                error!("{}: FAILED: {:#}", name, err);
+            }
+            Err(err) => {
+                failed += 1;
+                error!("Poller task panicked: {}", err);
+            }
+        }
+    }
+
+    for merge_handle in merge_handles {
+        merge_handle.await?;
+    }
+
+    log_client_stats(client);
+
+    if failed > 0 {
+        bail!("{} of {} pollers failed", failed, total);
+    }
+    info!("All {} pollers completed successfully", total);
+
+    Ok(())
+}
+
+async fn send_once_result(
+    result: Result<intersight_poller::IntersightMetricBatch>,
+    router: &metric_merger::MetricRouter,
+) -> Result<usize> {
+    let batch = result?;
+    let metric_count = batch.iter().map(|rm| rm.metrics.len()).sum();
+    router.deliver(batch).await?;
+    Ok(metric_count)
+}
+
+/// Periodically logs how many Intersight API requests were shared through
+/// request coalescing and the response cache
+fn start_client_stats_logger(client: coalescing_client::CoalescingClient) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(Duration::from_secs(CLIENT_STATS_INTERVAL_SECS));
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            log_client_stats(&client);
+        }
+    });
+}
+
+fn log_client_stats(client: &coalescing_client::CoalescingClient) {
+    let stats = client.stats();
+    info!(
+        "Intersight API requests: {} total, {} coalesced with in-flight requests, {} served from cache",
+        stats.requests, stats.coalesced, stats.cache_hits
+    );
+}
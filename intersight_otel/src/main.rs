@@ -1,104 +1,14 @@
-use anyhow::{Context, Result};
+use anyhow::Result;
+use clap::Parser;
 
 #[macro_use]
 extern crate log;
 
-mod attribute_enricher;
-mod config;
-mod intersight_poller;
-mod metric_merger;
-
 #[tokio::main]
 async fn main() -> Result<()> {
     env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
     info!("intersight_otel starting up");
 
-    let config = config::GlobalConfig::new().context("Unable to load config")?;
-    info!(
-        "Using Intersight key_id {} and key_file {}",
-        config.key_id(),
-        config.key_file
-    );
-
-    // let key_bytes = fs::read(&config.key_file)?;
-    // let client = intersight_api::Client::from_key_bytes(config.key_id(), &key_bytes, None)?;
-
-    let mut intersight_config = intersight_api::config::Config::new()
-        .with_key_id(config.key_id())
-        .with_key_file(&config.key_file)?;
-
-    if let Some(intersight_host) = config.intersight_host {
-        intersight_config = intersight_config.with_host(&intersight_host);
-    }
-
-    if let Some(intersight_accpet_invalid_certs) = config.intersight_accept_invalid_certs {
-        intersight_config = intersight_config.with_insecure(intersight_accpet_invalid_certs)
-    }
-
-    let client = intersight_config.build_client()?;
-
-    // Create a multi-producer single-consumer channel for poller tasks to send metrics to the metric_merger task
-    let (metric_chan_tx, metric_chan_rx) = tokio::sync::mpsc::channel(32);
-
-    let merge_handle =
-        metric_merger::start_metric_merger(metric_chan_rx, &config.otel_collector_endpoint);
-
-    // Build the shared enricher map once
-    let enricher_map = attribute_enricher::build_enricher_map(
-        config.enrichers.as_deref().unwrap_or_default(),
-        &client,
-    );
-
-    let mut poller_handles: Vec<tokio::task::JoinHandle<()>> = vec![];
-
-    // Start all the pollers based on the config file(s)
-    if let Some(poller_configs) = config.pollers {
-        for poller_config in poller_configs {
-            let enrichers = attribute_enricher::resolve_enrichers(
-                poller_config.enrichers.as_deref().unwrap_or_default(),
-                &enricher_map,
-            );
-            let handle = intersight_poller::start_intersight_poller(
-                metric_chan_tx.clone(),
-                &client,
-                &poller_config,
-                enrichers,
-            )?;
-            poller_handles.push(handle);
-        }
-    }
-
-    // Start all the timeseries pollers based on the config file(s)
-    if let Some(tspoller_configs) = config.tspollers {
-        for tspoller_config in tspoller_configs {
-            let enrichers = attribute_enricher::resolve_enrichers(
-                tspoller_config.enrichers.as_deref().unwrap_or_default(),
-                &enricher_map,
-            );
-            let handle = intersight_poller::start_intersight_tspoller(
-                metric_chan_tx.clone(),
-                &client,
-                &tspoller_config,
-                enrichers,
-            )?;
-            poller_handles.push(handle);
-        }
-    }
-
-    // Supervise poller tasks — they should never exit. If one does (panic or bug),
-    // log loudly so the silent metric gap doesn't go unnoticed.
-    for handle in poller_handles {
-        tokio::spawn(async move {
-            match handle.await {
-                Ok(()) => error!("Poller task exited unexpectedly — no further metrics from this poller"),
-                Err(e) => error!("Poller task panicked: {} — no further metrics from this poller", e),
-            }
-        });
-    }
-
-    // Keep running until the metric_merger finishes (i.e. never)
-    merge_handle.await?;
-
-    Ok(())
+    intersight_otel::run(intersight_otel::config::Args::parse()).await
 }
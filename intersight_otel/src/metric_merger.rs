@@ -1,6 +1,13 @@
-use crate::intersight_poller::{IntersightMetricBatch, IntersightResourceMetrics};
+use crate::config::ExporterConfig;
+use crate::console_exporter::{ConsoleExporter, ConsoleFormat};
+use crate::file_exporter::FileExporter;
+use crate::intersight_poller::{
+    IntersightMetric, IntersightMetricBatch, IntersightResourceMetrics, MetricKind,
+};
+use crate::prometheus_exporter::{self, PrometheusExporter};
 
-use opentelemetry_proto::tonic::common::v1::{AnyValue, KeyValue};
+use anyhow::{anyhow, bail, Context, Result};
+use opentelemetry_proto::tonic::common::v1::{any_value, AnyValue, KeyValue};
 use opentelemetry_proto::tonic::metrics::v1::number_data_point::Value;
 use opentelemetry_proto::{
     self,
@@ -10,57 +17,303 @@ use opentelemetry_proto::{
         },
         common::v1::InstrumentationScope,
         metrics::v1::{
-            metric::Data, Gauge, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics,
+            metric::Data, summary_data_point::ValueAtQuantile, AggregationTemporality, Gauge,
+            Histogram, HistogramDataPoint, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics,
+            Sum, Summary, SummaryDataPoint,
         },
         resource::v1::Resource,
     },
 };
-use std::time::SystemTime;
-use tokio::sync::mpsc::Receiver;
-use tokio::task::JoinHandle;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+use tokio::sync::mpsc::{self, error::TrySendError, Receiver, Sender};
+use tokio::sync::oneshot;
+use tokio::task::{self, JoinHandle};
 use tonic::transport::{self, Channel};
 
+pub enum Exporter {
+    Otlp(String),
+    Prometheus(Arc<PrometheusExporter>),
+    Stdout(ConsoleExporter),
+    File(Arc<Mutex<FileExporter>>),
+}
+
+/// The settings of an exporter once its configuration has been validated
+pub enum ExporterSpec<'a> {
+    Otlp { endpoint: &'a str },
+    Prometheus { listen_address: &'a str },
+    Stdout(ConsoleFormat),
+    File { path: &'a str },
+}
+
+/// A problem with an exporter's configuration and the field it concerns
+#[derive(Debug)]
+pub struct ExporterConfigError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+/// Validates an exporter's configuration without starting anything. Exporters
+/// are built from the result, so the `check` subcommand reports exactly the
+/// problems that would stop them from starting.
+pub fn exporter_spec(config: &ExporterConfig) -> Result<ExporterSpec<'_>, ExporterConfigError> {
+    let error = |field, message: &str| ExporterConfigError {
+        field,
+        message: message.to_string(),
+    };
+    match config.exporter_type.as_str() {
+        "otlp" => match &config.endpoint {
+            Some(endpoint) => Ok(ExporterSpec::Otlp { endpoint }),
+            None => Err(error("endpoint", "otlp exporter requires an endpoint")),
+        },
+        "prometheus" => match &config.listen_address {
+            Some(listen_address) => Ok(ExporterSpec::Prometheus { listen_address }),
+            None => Err(error(
+                "listen_address",
+                "prometheus exporter requires a listen_address",
+            )),
+        },
+        "stdout" => ConsoleFormat::from_config(config.format.as_deref())
+            .map(ExporterSpec::Stdout)
+            .map_err(|err| error("format", &err.to_string())),
+        "file" => match &config.path {
+            Some(path) => Ok(ExporterSpec::File { path }),
+            None => Err(error("path", "file exporter requires a path")),
+        },
+        other => Err(error("type", &format!("invalid exporter type '{}'", other))),
+    }
+}
+
+impl Exporter {
+    async fn from_config(config: &ExporterConfig) -> Result<Exporter> {
+        let spec = exporter_spec(config)
+            .map_err(|err| anyhow!("Exporter '{}': {}", config.name, err.message))?;
+        match spec {
+            ExporterSpec::Otlp { endpoint } => Ok(Exporter::Otlp(endpoint.to_string())),
+            ExporterSpec::Prometheus { listen_address } => {
+                let exporter = Arc::new(PrometheusExporter::new(Duration::from_secs(
+                    config.series_ttl(),
+                )));
+                prometheus_exporter::start_prometheus_server(exporter.clone(), listen_address)
+                    .await?;
+                Ok(Exporter::Prometheus(exporter))
+            }
+            ExporterSpec::Stdout(format) => Ok(Exporter::Stdout(ConsoleExporter::new(format))),
+            ExporterSpec::File { path } => Ok(Exporter::File(Arc::new(Mutex::new(
+                FileExporter::new(path, config.max_file_size(), config.max_files())?,
+            )))),
+        }
+    }
+}
+
+/// A batch queued for an exporter. One-shot runs and backfills pass an `ack`
+/// channel to find out whether the batch was exported.
+pub struct ExportRequest {
+    pub(crate) batch: IntersightMetricBatch,
+    pub(crate) ack: Option<oneshot::Sender<Result<()>>>,
+}
+
+/// Routes a poller's metric batches to every exporter it has been configured to use.
+#[derive(Clone)]
+pub struct MetricRouter {
+    exporters: Vec<(String, Sender<ExportRequest>)>,
+}
+
+impl MetricRouter {
+    /// Queues the batch for every exporter without waiting, so a slow or
+    /// stalled exporter can't hold up the poller or the other exporters. The
+    /// batch is dropped for exporters whose queue is full.
+    pub fn send(&self, batch: IntersightMetricBatch) {
+        for (name, tx) in &self.exporters {
+            let request = ExportRequest {
+                batch: batch.clone(),
+                ack: None,
+            };
+            match tx.try_send(request) {
+                Ok(()) => {}
+                Err(TrySendError::Full(_)) => {
+                    warn!("Exporter '{}' is falling behind, dropping batch", name);
+                }
+                Err(TrySendError::Closed(_)) => {
+                    error!("metrics receiver for exporter '{}' dropped", name);
+                }
+            }
+        }
+    }
+
+    /// Queues the batch for every exporter, waiting for room in their queues,
+    /// and then waits until they have all exported it. Used by one-shot runs
+    /// and backfills, where no batch may be dropped and export failures matter.
+    pub async fn deliver(&self, batch: IntersightMetricBatch) -> Result<()> {
+        let mut acks = vec![];
+        let mut failures = vec![];
+        for (name, tx) in &self.exporters {
+            let (ack, ack_rx) = oneshot::channel();
+            let request = ExportRequest {
+                batch: batch.clone(),
+                ack: Some(ack),
+            };
+            match tx.send(request).await {
+                Ok(()) => acks.push((name, ack_rx)),
+                Err(_) => failures.push(format!("exporter '{}' is not running", name)),
+            }
+        }
+
+        // The exporters work concurrently, so waiting for each in turn only
+        // takes as long as the slowest
+        for (name, ack_rx) in acks {
+            match ack_rx.await {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => failures.push(format!("exporter '{}': {:#}", name, err)),
+                Err(_) => failures.push(format!("exporter '{}' did not export the batch", name)),
+            }
+        }
+
+        if !failures.is_empty() {
+            bail!("export failed: {}", failures.join("; "));
+        }
+        Ok(())
+    }
+}
+
+/// Starts a metric merger task for each configured exporter, returning the
+/// channels that feed them (keyed by exporter name) and their task handles.
+pub async fn start_exporters(
+    configs: &[ExporterConfig],
+) -> Result<(HashMap<String, Sender<ExportRequest>>, Vec<JoinHandle<()>>)> {
+    if configs.is_empty() {
+        bail!("No exporter configured: set otel_collector_endpoint, prometheus_listen_address or add [[exporters]]");
+    }
+
+    let mut senders = HashMap::new();
+    let mut handles = vec![];
+    for config in configs {
+        if senders.contains_key(&config.name) {
+            bail!("Duplicate exporter name '{}'", config.name);
+        }
+        let exporter = Exporter::from_config(config).await?;
+
+        // Each exporter gets its own multi-producer single-consumer channel so a
+        // slow backend doesn't hold up the others
+        let (tx, rx) = mpsc::channel(32);
+        handles.push(start_metric_merger(rx, &config.name, exporter));
+        senders.insert(config.name.clone(), tx);
+    }
+
+    Ok((senders, handles))
+}
+
+/// Builds the router for a poller from its list of exporter names. An empty
+/// list routes to every exporter.
+pub fn resolve_exporters(
+    names: &[String],
+    map: &HashMap<String, Sender<ExportRequest>>,
+) -> Result<MetricRouter> {
+    let mut exporters = vec![];
+    if names.is_empty() {
+        for (name, tx) in map {
+            exporters.push((name.clone(), tx.clone()));
+        }
+        exporters.sort_by(|a, b| a.0.cmp(&b.0));
+    } else {
+        for name in names {
+            match map.get(name) {
+                Some(tx) => exporters.push((name.clone(), tx.clone())),
+                None => bail!("Exporter '{}' not found in config", name),
+            }
+        }
+    }
+
+    Ok(MetricRouter { exporters })
+}
+
 pub fn start_metric_merger(
-    mut metric_chan: Receiver<IntersightMetricBatch>,
-    otel_collector_endpoint: &str,
+    mut metric_chan: Receiver<ExportRequest>,
+    name: &str,
+    mut exporter: Exporter,
 ) -> JoinHandle<()> {
-    let otel_collector_endpoint = String::from(otel_collector_endpoint);
+    let name = String::from(name);
     tokio::spawn(async move {
-        let _ctrl = init_metrics_client(&otel_collector_endpoint).await;
-        let mut client;
-        if let Err(err) = _ctrl {
-            error!("Failed to initialise metrics client: {}", err);
-            return;
-        } else {
-            client = _ctrl.unwrap();
-        }
+        // The OTLP client, connected on the first batch
+        let mut client = None;
+
+        info!("Starting metric merger task for exporter '{}'", name);
+        while let Some(request) = metric_chan.recv().await {
+            let metric_batch = request.batch;
+            for rm in &metric_batch {
+                debug!(
+                    "Received resouce metrics {:?} = {:?}",
+                    rm.attributes, rm.metrics
+                );
+            }
 
-        info!("Starting metric merger task");
-        loop {
-            if let Some(metric_batch) = metric_chan.recv().await {
-                for rm in metric_batch {
-                    info!(
-                        "Received resouce metrics {:?} = {:?}",
-                        rm.attributes, rm.metrics
-                    );
-
-                    let resource_metrics = ResourceMetrics::from(rm);
-
-                    let res = client
-                        .export(ExportMetricsServiceRequest {
-                            resource_metrics: vec![resource_metrics],
-                        })
-                        .await;
-
-                    if let Err(err) = res {
-                        error!("Error sending metrics: {}", err);
-                    }
+            let result = match &mut exporter {
+                Exporter::Otlp(otel_collector_endpoint) => {
+                    export_otlp(&mut client, otel_collector_endpoint, metric_batch).await
                 }
+                Exporter::Prometheus(prometheus_exporter) => {
+                    prometheus_exporter.record_batch(&metric_batch);
+                    Ok(())
+                }
+                Exporter::Stdout(console_exporter) => {
+                    console_exporter.export(metric_batch);
+                    Ok(())
+                }
+                Exporter::File(file_exporter) => {
+                    // Writing and rotating files blocks, so it runs on the
+                    // blocking thread pool rather than in this task
+                    let file_exporter = file_exporter.clone();
+                    task::spawn_blocking(move || file_exporter.lock().unwrap().export(metric_batch))
+                        .await
+                        .context("writer task failed")
+                        .and_then(|result| result.context("error writing metrics"))
+                }
+            };
+
+            if let Err(err) = &result {
+                error!("Exporter '{}': {:#}", name, err);
+            }
+            if let Some(ack) = request.ack {
+                let _ = ack.send(result);
             }
         }
     })
 }
 
+/// Sends a batch to the OTLP collector, connecting first if there is no
+/// client yet. A failed connection is retried with the next batch, so the
+/// exporter recovers once the collector is reachable.
+async fn export_otlp(
+    client: &mut Option<MetricsServiceClient<Channel>>,
+    otel_collector_endpoint: &str,
+    metric_batch: IntersightMetricBatch,
+) -> Result<()> {
+    let connected = match client.take() {
+        Some(connected) => connected,
+        None => init_metrics_client(otel_collector_endpoint)
+            .await
+            .with_context(|| format!("unable to connect to {}", otel_collector_endpoint))?,
+    };
+    let client = client.insert(connected);
+
+    let mut result = Ok(());
+    for rm in metric_batch {
+        let resource_metrics = ResourceMetrics::from(rm);
+
+        let res = client
+            .export(ExportMetricsServiceRequest {
+                resource_metrics: vec![resource_metrics],
+            })
+            .await;
+
+        if let Err(err) = res {
+            result = Err(anyhow!("error sending metrics: {}", err));
+        }
+    }
+    result
+}
+
 async fn init_metrics_client(
     otel_collector_endpoint: &str,
 ) -> Result<MetricsServiceClient<Channel>, tonic::transport::Error> {
@@ -70,46 +323,143 @@ async fn init_metrics_client(
     Ok(MetricsServiceClient::new(channel))
 }
 
+/// The OTLP data of a metric according to its kind. Sums from timeseries
+/// pollers are delta sums over the Druid window or bucket.
+fn metric_data(m: &IntersightMetric) -> Data {
+    let start_time_unix_nano = unix_nanos(m.start_time);
+    let time_unix_nano = unix_nanos(m.time);
+    let attributes: Vec<KeyValue> = m
+        .attributes
+        .iter()
+        .map(|(k, v)| KeyValue {
+            key: k.clone(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(v.clone())),
+            }),
+        })
+        .chain(m.datapoint_attributes.iter().cloned())
+        .collect();
+    let number_data_point = || NumberDataPoint {
+        attributes: attributes.clone(),
+        start_time_unix_nano,
+        time_unix_nano,
+        exemplars: vec![],
+        flags: 0,
+        value: Some(Value::AsDouble(m.value)),
+    };
+
+    match &m.kind {
+        MetricKind::Gauge => Data::Gauge(Gauge {
+            data_points: vec![number_data_point()],
+        }),
+        MetricKind::Sum {
+            cumulative,
+            monotonic,
+        } => Data::Sum(Sum {
+            data_points: vec![number_data_point()],
+            aggregation_temporality: temporality(*cumulative),
+            is_monotonic: *monotonic,
+        }),
+        MetricKind::Histogram {
+            bounds,
+            bucket_counts,
+            sum,
+        } => Data::Histogram(Histogram {
+            data_points: vec![HistogramDataPoint {
+                attributes: attributes.clone(),
+                start_time_unix_nano,
+                time_unix_nano,
+                count: m.value as u64,
+                sum: *sum,
+                bucket_counts: bucket_counts.clone(),
+                explicit_bounds: bounds.clone(),
+                exemplars: vec![],
+                flags: 0,
+                min: None,
+                max: None,
+            }],
+            aggregation_temporality: temporality(false),
+        }),
+        MetricKind::Summary { quantiles, sum } => Data::Summary(Summary {
+            data_points: vec![SummaryDataPoint {
+                attributes,
+                start_time_unix_nano,
+                time_unix_nano,
+                count: m.value as u64,
+                sum: *sum,
+                quantile_values: quantiles
+                    .iter()
+                    .map(|(quantile, value)| ValueAtQuantile {
+                        quantile: *quantile,
+                        value: *value,
+                    })
+                    .collect(),
+                flags: 0,
+            }],
+        }),
+    }
+}
+
+/// Adds the data points of `data` to `existing` if both are the same kind of
+/// metric, or returns `data` unchanged
+fn append_data_points(existing: &mut Data, data: Data) -> Result<(), Data> {
+    match (existing, data) {
+        (Data::Gauge(a), Data::Gauge(b)) => a.data_points.extend(b.data_points),
+        (Data::Sum(a), Data::Sum(b))
+            if a.aggregation_temporality == b.aggregation_temporality
+                && a.is_monotonic == b.is_monotonic =>
+        {
+            a.data_points.extend(b.data_points)
+        }
+        (Data::Histogram(a), Data::Histogram(b))
+            if a.aggregation_temporality == b.aggregation_temporality =>
+        {
+            a.data_points.extend(b.data_points)
+        }
+        (Data::Summary(a), Data::Summary(b)) => a.data_points.extend(b.data_points),
+        (_, data) => return Err(data),
+    }
+    Ok(())
+}
+
+fn temporality(cumulative: bool) -> i32 {
+    if cumulative {
+        AggregationTemporality::Cumulative as i32
+    } else {
+        AggregationTemporality::Delta as i32
+    }
+}
+
+fn unix_nanos(time: SystemTime) -> u64 {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_nanos() as u64
+}
+
 impl From<IntersightResourceMetrics> for ResourceMetrics {
     fn from(value: IntersightResourceMetrics) -> Self {
-        let mut metrics = vec![];
+        let mut metrics: Vec<Metric> = vec![];
         // let start_time = value.start_time.unwrap_or(SystemTime::now());
         for m in value.metrics {
+            // Metrics with the same name, such as one per data point attribute
+            // value, become data points of a single OTLP metric
+            let mut data = metric_data(&m);
+            if let Some(Some(existing)) = metrics
+                .iter_mut()
+                .find(|e| e.name == m.name && e.unit == m.unit)
+                .map(|e| e.data.as_mut())
+            {
+                match append_data_points(existing, data) {
+                    Ok(()) => continue,
+                    Err(unmerged) => data = unmerged,
+                }
+            }
             metrics.push(Metric {
-                name: m.name,
-                description: "".to_string(),
-                unit: "".to_string(),
+                description: m.description.clone(),
+                unit: m.unit.clone(),
                 metadata: vec![],
-                data: Some(Data::Gauge(Gauge {
-                    data_points: vec![NumberDataPoint {
-                        attributes: vec![],
-                        start_time_unix_nano: m
-                            .start_time
-                            .duration_since(SystemTime::UNIX_EPOCH)
-                            .expect("Time went backwards")
-                            .as_nanos() as u64,
-                        time_unix_nano: m
-                            .time
-                            .duration_since(SystemTime::UNIX_EPOCH)
-                            .expect("Time went backwards")
-                            .as_nanos() as u64,
-                        // start_time_unix_nano: start_time
-                        //     .checked_sub(Duration::from_secs(m.timestamp_offset))
-                        //     .expect("Unable to apply timestamp offset")
-                        //     .duration_since(UNIX_EPOCH)
-                        //     .expect("Time went backwards")
-                        //     .as_nanos() as u64,
-                        // time_unix_nano: SystemTime::now()
-                        //     .checked_sub(Duration::from_secs(m.timestamp_offset))
-                        //     .expect("Unable to apply timestamp offset")
-                        //     .duration_since(UNIX_EPOCH)
-                        //     .expect("Time went backwards")
-                        //     .as_nanos() as u64,
-                        exemplars: vec![],
-                        flags: 0,
-                        value: Some(Value::AsDouble(m.value)),
-                    }],
-                })),
+                data: Some(data),
+                name: m.name,
             })
         }
         let mut resource_attributes = value.attributes;
@@ -142,3 +492,214 @@ impl From<IntersightResourceMetrics> for ResourceMetrics {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeMap;
+
+    fn make_map(names: &[&str]) -> HashMap<String, Sender<ExportRequest>> {
+        names
+            .iter()
+            .map(|name| (name.to_string(), mpsc::channel(1).0))
+            .collect()
+    }
+
+    fn router_names(router: &MetricRouter) -> Vec<&str> {
+        router.exporters.iter().map(|(n, _)| n.as_str()).collect()
+    }
+
+    #[test]
+    fn test_resolve_exporters_defaults_to_all() {
+        let map = make_map(&["prod", "debug"]);
+        let router = resolve_exporters(&[], &map).unwrap();
+        assert_eq!(router_names(&router), vec!["debug", "prod"]);
+    }
+
+    #[test]
+    fn test_resolve_exporters_selected() {
+        let map = make_map(&["prod", "team", "debug"]);
+        let names = vec!["team".to_string()];
+        let router = resolve_exporters(&names, &map).unwrap();
+        assert_eq!(router_names(&router), vec!["team"]);
+    }
+
+    #[test]
+    fn test_resolve_exporters_unknown_is_error() {
+        let map = make_map(&["prod"]);
+        let names = vec!["prod".to_string(), "nonexistent".to_string()];
+        assert!(resolve_exporters(&names, &map).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_router_sends_to_each_exporter() {
+        let (tx_a, mut rx_a) = mpsc::channel(1);
+        let (tx_b, mut rx_b) = mpsc::channel(1);
+        let router = MetricRouter {
+            exporters: vec![("a".to_string(), tx_a), ("b".to_string(), tx_b)],
+        };
+
+        router.send(vec![IntersightResourceMetrics::default()]);
+
+        assert_eq!(rx_a.recv().await.unwrap().batch.len(), 1);
+        assert_eq!(rx_b.recv().await.unwrap().batch.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_router_full_exporter_does_not_block_others() {
+        let (tx_a, rx_a) = mpsc::channel(1);
+        let (tx_b, mut rx_b) = mpsc::channel(1);
+        let router = MetricRouter {
+            exporters: vec![("a".to_string(), tx_a), ("b".to_string(), tx_b)],
+        };
+
+        // Exporter a never reads, so its queue stays full after the first batch
+        router.send(vec![IntersightResourceMetrics::default()]);
+        assert_eq!(rx_b.recv().await.unwrap().batch.len(), 1);
+        router.send(vec![IntersightResourceMetrics::default(); 2]);
+        assert_eq!(rx_b.recv().await.unwrap().batch.len(), 2);
+        drop(rx_a);
+    }
+
+    #[tokio::test]
+    async fn test_router_deliver_reports_export_failures() {
+        let (tx_ok, mut rx_ok) = mpsc::channel::<ExportRequest>(1);
+        let (tx_failing, mut rx_failing) = mpsc::channel::<ExportRequest>(1);
+        let (tx_stopped, rx_stopped) = mpsc::channel(1);
+        drop(rx_stopped);
+        tokio::spawn(async move {
+            while let Some(request) = rx_ok.recv().await {
+                request.ack.unwrap().send(Ok(())).unwrap();
+            }
+        });
+        tokio::spawn(async move {
+            while let Some(request) = rx_failing.recv().await {
+                let _ = request
+                    .ack
+                    .unwrap()
+                    .send(Err(anyhow!("collector unavailable")));
+            }
+        });
+
+        let router = MetricRouter {
+            exporters: vec![("ok".to_string(), tx_ok.clone())],
+        };
+        assert!(router.deliver(vec![]).await.is_ok());
+
+        let router = MetricRouter {
+            exporters: vec![
+                ("ok".to_string(), tx_ok),
+                ("failing".to_string(), tx_failing),
+                ("stopped".to_string(), tx_stopped),
+            ],
+        };
+        assert_eq!(
+            router.deliver(vec![]).await.unwrap_err().to_string(),
+            "export failed: exporter 'stopped' is not running; \
+             exporter 'failing': collector unavailable"
+        );
+    }
+
+    #[test]
+    fn test_metric_kinds_to_otlp() {
+        let metric = |kind| {
+            let mut m = IntersightMetric::new(
+                "m",
+                6.0,
+                None,
+                SystemTime::UNIX_EPOCH + Duration::from_secs(600),
+                SystemTime::UNIX_EPOCH + Duration::from_secs(1200),
+            );
+            m.kind = kind;
+            m
+        };
+
+        let Data::Sum(sum) = metric_data(&metric(MetricKind::Sum {
+            cumulative: false,
+            monotonic: true,
+        })) else {
+            panic!("expected a sum");
+        };
+        assert_eq!(
+            sum.aggregation_temporality,
+            AggregationTemporality::Delta as i32
+        );
+        assert!(sum.is_monotonic);
+        assert_eq!(sum.data_points[0].start_time_unix_nano, 600_000_000_000);
+        assert_eq!(sum.data_points[0].time_unix_nano, 1_200_000_000_000);
+
+        let Data::Histogram(histogram) = metric_data(&metric(MetricKind::Histogram {
+            bounds: vec![10.0, 100.0],
+            bucket_counts: vec![3, 2, 1],
+            sum: Some(250.0),
+        })) else {
+            panic!("expected a histogram");
+        };
+        let point = &histogram.data_points[0];
+        assert_eq!(point.count, 6);
+        assert_eq!(point.sum, Some(250.0));
+        assert_eq!(point.bucket_counts, vec![3, 2, 1]);
+        assert_eq!(point.explicit_bounds, vec![10.0, 100.0]);
+
+        let Data::Summary(summary) = metric_data(&metric(MetricKind::Summary {
+            quantiles: vec![(0.5, 12.0)],
+            sum: 100.0,
+        })) else {
+            panic!("expected a summary");
+        };
+        let point = &summary.data_points[0];
+        assert_eq!((point.count, point.sum), (6, 100.0));
+        assert_eq!(point.quantile_values[0].quantile, 0.5);
+        assert_eq!(point.quantile_values[0].value, 12.0);
+
+        assert!(matches!(
+            metric_data(&metric(MetricKind::Gauge)),
+            Data::Gauge(_)
+        ));
+    }
+
+    #[test]
+    fn test_datapoint_attributes_to_otlp() {
+        let metric = |name: &str, direction: &str| {
+            IntersightMetric::new(
+                name,
+                1.0,
+                Some(BTreeMap::from([(
+                    "direction".to_string(),
+                    direction.to_string(),
+                )])),
+                SystemTime::UNIX_EPOCH,
+                SystemTime::UNIX_EPOCH,
+            )
+        };
+        let mut packets = metric("packets", "receive");
+        packets.kind = MetricKind::Sum {
+            cumulative: false,
+            monotonic: true,
+        };
+        let rm = IntersightResourceMetrics {
+            metrics: vec![
+                metric("bytes", "receive"),
+                packets,
+                metric("bytes", "transmit"),
+            ],
+            ..Default::default()
+        };
+
+        let metrics = ResourceMetrics::from(rm).scope_metrics.remove(0).metrics;
+        let names: Vec<&str> = metrics.iter().map(|m| m.name.as_str()).collect();
+        assert_eq!(names, ["bytes", "packets"]);
+
+        let Some(Data::Gauge(gauge)) = &metrics[0].data else {
+            panic!("expected a gauge");
+        };
+        let directions: Vec<&KeyValue> =
+            gauge.data_points.iter().map(|p| &p.attributes[0]).collect();
+        assert_eq!(directions.len(), 2);
+        assert_eq!(directions[0].key, "direction");
+        assert_eq!(
+            directions[1].value.as_ref().unwrap().value,
+            Some(any_value::Value::StringValue("transmit".to_string()))
+        );
+    }
+}
@@ -0,0 +1,467 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::State,
+    http::{header, HeaderMap},
+    response::IntoResponse,
+    routing::get,
+    Router,
+};
+use opentelemetry_proto::tonic::common::v1::{any_value, KeyValue};
+use tokio::task::JoinHandle;
+
+use crate::intersight_poller::{IntersightMetricBatch, MetricKind};
+
+const PROMETHEUS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+type Labels = Vec<(String, String)>;
+
+struct Sample {
+    value: f64,
+    kind: MetricKind,
+    description: String,
+    last_seen: Instant,
+}
+
+/// Keeps the latest value of every metric series received from the pollers so
+/// it can be scraped in the Prometheus text format. Series that have not been
+/// reported for longer than `ttl` are dropped.
+pub struct PrometheusExporter {
+    series: Mutex<BTreeMap<String, BTreeMap<Labels, Sample>>>,
+    ttl: Duration,
+}
+
+impl PrometheusExporter {
+    pub fn new(ttl: Duration) -> Self {
+        PrometheusExporter {
+            series: Mutex::new(BTreeMap::new()),
+            ttl,
+        }
+    }
+
+    pub fn record_batch(&self, batch: &IntersightMetricBatch) {
+        let now = Instant::now();
+        let mut series = self.series.lock().unwrap();
+
+        for rm in batch {
+            let resource_labels = sanitize_labels(&rm.attributes);
+            for m in &rm.metrics {
+                let mut labels = resource_labels.clone();
+                for (k, v) in &m.attributes {
+                    labels.insert(sanitize_label_name(k), v.clone());
+                }
+                labels.extend(sanitize_labels(&m.datapoint_attributes));
+
+                series
+                    .entry(sanitize_metric_name(&m.name))
+                    .or_default()
+                    .insert(
+                        labels.into_iter().collect(),
+                        Sample {
+                            value: m.value,
+                            kind: m.kind.clone(),
+                            description: m.description.clone(),
+                            last_seen: now,
+                        },
+                    );
+            }
+        }
+    }
+
+    pub fn render(&self, openmetrics: bool) -> String {
+        let mut series = self.series.lock().unwrap();
+        self.expire(&mut series);
+
+        let mut out = String::new();
+        for (name, samples) in series.iter() {
+            let first = samples.values().next();
+            let metric_type = first.map_or("gauge", |s| metric_type(&s.kind));
+            // Counter samples end in _total, which OpenMetrics leaves out of
+            // the metric family name
+            let name = match metric_type {
+                "counter" if !name.ends_with("_total") => format!("{}_total", name),
+                _ => name.clone(),
+            };
+            let family = match metric_type {
+                "counter" if openmetrics => name.trim_end_matches("_total"),
+                _ => &name,
+            };
+            if let Some(description) = first.map(|s| &s.description).filter(|d| !d.is_empty()) {
+                let _ = writeln!(
+                    out,
+                    "# HELP {} {}",
+                    family,
+                    escape_help(description, openmetrics)
+                );
+            }
+            let _ = writeln!(out, "# TYPE {} {}", family, metric_type);
+            for (labels, sample) in samples {
+                write_samples(&mut out, &name, labels, sample);
+            }
+        }
+        if openmetrics {
+            out.push_str("# EOF\n");
+        }
+        out
+    }
+
+    fn expire(&self, series: &mut BTreeMap<String, BTreeMap<Labels, Sample>>) {
+        let now = Instant::now();
+        for samples in series.values_mut() {
+            samples.retain(|_, s| now.duration_since(s.last_seen) <= self.ttl);
+        }
+        series.retain(|_, samples| !samples.is_empty());
+    }
+}
+
+/// The Prometheus type of a metric. Only cumulative monotonic sums are
+/// counters; delta sums are exposed as gauges of the latest window's value.
+fn metric_type(kind: &MetricKind) -> &'static str {
+    match kind {
+        MetricKind::Gauge => "gauge",
+        MetricKind::Sum {
+            cumulative: true,
+            monotonic: true,
+        } => "counter",
+        MetricKind::Sum { .. } => "gauge",
+        MetricKind::Histogram { .. } => "histogram",
+        MetricKind::Summary { .. } => "summary",
+    }
+}
+
+/// Writes the sample lines of a series: one line for gauges and counters,
+/// and the buckets or quantiles followed by `_sum` and `_count` for
+/// histograms and summaries
+fn write_samples(out: &mut String, name: &str, labels: &Labels, sample: &Sample) {
+    match &sample.kind {
+        MetricKind::Histogram {
+            bounds,
+            bucket_counts,
+            sum,
+        } => {
+            let bucket_name = format!("{}_bucket", name);
+            let mut cumulative_count = 0;
+            for (bound, count) in bounds.iter().zip(bucket_counts) {
+                cumulative_count += count;
+                let le = ("le", format_value(*bound));
+                write_sample(out, &bucket_name, labels, Some(le), cumulative_count as f64);
+            }
+            let le = ("le", "+Inf".to_string());
+            write_sample(out, &bucket_name, labels, Some(le), sample.value);
+            if let Some(sum) = sum {
+                write_sample(out, &format!("{}_sum", name), labels, None, *sum);
+            }
+            write_sample(out, &format!("{}_count", name), labels, None, sample.value);
+        }
+        MetricKind::Summary { quantiles, sum } => {
+            for (quantile, value) in quantiles {
+                let quantile = ("quantile", format_value(*quantile));
+                write_sample(out, name, labels, Some(quantile), *value);
+            }
+            write_sample(out, &format!("{}_sum", name), labels, None, *sum);
+            write_sample(out, &format!("{}_count", name), labels, None, sample.value);
+        }
+        _ => write_sample(out, name, labels, None, sample.value),
+    }
+}
+
+fn write_sample(
+    out: &mut String,
+    name: &str,
+    labels: &Labels,
+    extra_label: Option<(&str, String)>,
+    value: f64,
+) {
+    out.push_str(name);
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(k, v)| (k.as_str(), v.clone()))
+        .chain(extra_label)
+        .map(|(k, v)| format!("{}=\"{}\"", k, escape_label_value(&v)))
+        .collect();
+    if !labels.is_empty() {
+        let _ = write!(out, "{{{}}}", labels.join(","));
+    }
+    let _ = writeln!(out, " {}", format_value(value));
+}
+
+pub async fn start_prometheus_server(
+    exporter: Arc<PrometheusExporter>,
+    listen_address: &str,
+) -> Result<JoinHandle<()>> {
+    let listener = tokio::net::TcpListener::bind(listen_address)
+        .await
+        .with_context(|| format!("Unable to listen on {}", listen_address))?;
+    info!(
+        "Serving Prometheus metrics on http://{}/metrics",
+        listen_address
+    );
+
+    let app = Router::new()
+        .route("/metrics", get(metrics_handler))
+        .with_state(exporter);
+
+    Ok(tokio::spawn(async move {
+        if let Err(err) = axum::serve(listener, app).await {
+            error!("Prometheus metrics server failed: {}", err);
+        }
+    }))
+}
+
+async fn metrics_handler(
+    State(exporter): State<Arc<PrometheusExporter>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    let openmetrics = headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/openmetrics-text"));
+    let content_type = if openmetrics {
+        OPENMETRICS_CONTENT_TYPE
+    } else {
+        PROMETHEUS_CONTENT_TYPE
+    };
+
+    (
+        [(header::CONTENT_TYPE, content_type)],
+        exporter.render(openmetrics),
+    )
+}
+
+fn sanitize_labels(attributes: &[KeyValue]) -> BTreeMap<String, String> {
+    attributes
+        .iter()
+        .filter_map(|kv| {
+            let value = match kv.value.as_ref()?.value.as_ref()? {
+                any_value::Value::StringValue(s) => s.clone(),
+                any_value::Value::BoolValue(b) => b.to_string(),
+                any_value::Value::IntValue(i) => i.to_string(),
+                any_value::Value::DoubleValue(d) => d.to_string(),
+                _ => return None,
+            };
+            Some((sanitize_label_name(&kv.key), value))
+        })
+        .collect()
+}
+
+/// Converts an OTel metric name (e.g. `intersight.hyperflex.read.iops`) into a
+/// valid Prometheus metric name (`intersight_hyperflex_read_iops`).
+pub fn sanitize_metric_name(name: &str) -> String {
+    sanitize(name, |c| c.is_ascii_alphanumeric() || c == '_' || c == ':')
+}
+
+/// Converts an OTel attribute key (e.g. `host.id`) into a valid Prometheus
+/// label name (`host_id`).
+pub fn sanitize_label_name(name: &str) -> String {
+    sanitize(name, |c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn sanitize(name: &str, valid: impl Fn(char) -> bool) -> String {
+    let mut out = String::with_capacity(name.len() + 1);
+    for c in name.chars() {
+        let c = if valid(c) { c } else { '_' };
+        // Collapse runs of replacement characters so "a..b" becomes "a_b"
+        if c == '_' && out.ends_with('_') {
+            continue;
+        }
+        out.push(c);
+    }
+    if out.is_empty() || out.starts_with(|c: char| c.is_ascii_digit()) {
+        out.insert(0, '_');
+    }
+    out
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// HELP text escapes backslashes and newlines; OpenMetrics escapes double
+/// quotes as well, like label values
+fn escape_help(help: &str, openmetrics: bool) -> String {
+    if openmetrics {
+        return escape_label_value(help);
+    }
+    help.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".to_string()
+    } else if value.is_infinite() {
+        if value > 0.0 { "+Inf" } else { "-Inf" }.to_string()
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::intersight_poller::{IntersightMetric, IntersightResourceMetrics};
+    use opentelemetry_proto::tonic::common::v1::AnyValue;
+    use std::time::SystemTime;
+
+    fn string_kv(key: &str, value: &str) -> KeyValue {
+        KeyValue {
+            key: key.to_string(),
+            value: Some(AnyValue {
+                value: Some(any_value::Value::StringValue(value.to_string())),
+            }),
+        }
+    }
+
+    fn make_batch(name: &str, value: f64, attributes: Vec<KeyValue>) -> IntersightMetricBatch {
+        vec![IntersightResourceMetrics {
+            attributes,
+            metrics: vec![IntersightMetric::new(
+                name,
+                value,
+                None,
+                SystemTime::now(),
+                SystemTime::now(),
+            )],
+            start_time: None,
+        }]
+    }
+
+    #[test]
+    fn test_sanitize_metric_name() {
+        assert_eq!(
+            sanitize_metric_name("intersight.hyperflex.read.iops"),
+            "intersight_hyperflex_read_iops"
+        );
+        assert_eq!(sanitize_metric_name("a..b-c"), "a_b_c");
+        assert_eq!(sanitize_metric_name("9lives"), "_9lives");
+        assert_eq!(sanitize_metric_name("ns:metric"), "ns:metric");
+    }
+
+    #[test]
+    fn test_sanitize_label_name() {
+        assert_eq!(sanitize_label_name("host.id"), "host_id");
+        assert_eq!(sanitize_label_name("ns:label"), "ns_label");
+    }
+
+    #[test]
+    fn test_render_includes_resource_attributes_as_labels() {
+        let exporter = PrometheusExporter::new(Duration::from_secs(60));
+        exporter.record_batch(&make_batch(
+            "intersight.alarms.count",
+            3.0,
+            vec![
+                string_kv("severity", "critical"),
+                string_kv("host.id", "a\"b"),
+            ],
+        ));
+
+        let out = exporter.render(false);
+        assert_eq!(
+            out,
+            "# TYPE intersight_alarms_count gauge\n\
+             intersight_alarms_count{host_id=\"a\\\"b\",severity=\"critical\"} 3\n"
+        );
+    }
+
+    #[test]
+    fn test_render_keeps_latest_value_per_series() {
+        let exporter = PrometheusExporter::new(Duration::from_secs(60));
+        exporter.record_batch(&make_batch("m", 1.0, vec![string_kv("a", "x")]));
+        exporter.record_batch(&make_batch("m", 2.0, vec![string_kv("a", "x")]));
+        exporter.record_batch(&make_batch("m", 5.0, vec![string_kv("a", "y")]));
+
+        let out = exporter.render(true);
+        assert_eq!(out, "# TYPE m gauge\nm{a=\"x\"} 2\nm{a=\"y\"} 5\n# EOF\n");
+    }
+
+    #[test]
+    fn test_render_help() {
+        let exporter = PrometheusExporter::new(Duration::from_secs(60));
+        let mut batch = make_batch("power", 120.0, vec![string_kv("host", "a")]);
+        batch[0].metrics[0].description = "Power \"drawn\"\nin watts".to_string();
+        exporter.record_batch(&batch);
+
+        assert_eq!(
+            exporter.render(false),
+            "# HELP power Power \"drawn\"\\nin watts\n\
+             # TYPE power gauge\n\
+             power{host=\"a\"} 120\n"
+        );
+        assert!(exporter
+            .render(true)
+            .starts_with("# HELP power Power \\\"drawn\\\"\\nin watts\n"));
+    }
+
+    #[test]
+    fn test_render_metric_kinds() {
+        let exporter = PrometheusExporter::new(Duration::from_secs(60));
+        let kinds = [
+            (
+                "errors",
+                2.0,
+                MetricKind::Sum {
+                    cumulative: true,
+                    monotonic: true,
+                },
+            ),
+            (
+                "latency",
+                6.0,
+                MetricKind::Histogram {
+                    bounds: vec![10.0, 100.0],
+                    bucket_counts: vec![3, 2, 1],
+                    sum: Some(250.0),
+                },
+            ),
+            (
+                "response",
+                4.0,
+                MetricKind::Summary {
+                    quantiles: vec![(0.5, 12.0), (0.99, 80.0)],
+                    sum: 100.0,
+                },
+            ),
+        ];
+        for (name, value, kind) in kinds {
+            let mut batch = make_batch(name, value, vec![string_kv("host", "a")]);
+            batch[0].metrics[0].kind = kind;
+            exporter.record_batch(&batch);
+        }
+
+        assert_eq!(
+            exporter.render(false),
+            "# TYPE errors_total counter\n\
+             errors_total{host=\"a\"} 2\n\
+             # TYPE latency histogram\n\
+             latency_bucket{host=\"a\",le=\"10\"} 3\n\
+             latency_bucket{host=\"a\",le=\"100\"} 5\n\
+             latency_bucket{host=\"a\",le=\"+Inf\"} 6\n\
+             latency_sum{host=\"a\"} 250\n\
+             latency_count{host=\"a\"} 6\n\
+             # TYPE response summary\n\
+             response{host=\"a\",quantile=\"0.5\"} 12\n\
+             response{host=\"a\",quantile=\"0.99\"} 80\n\
+             response_sum{host=\"a\"} 100\n\
+             response_count{host=\"a\"} 4\n"
+        );
+        assert!(exporter
+            .render(true)
+            .starts_with("# TYPE errors counter\nerrors_total{host=\"a\"} 2\n"));
+    }
+
+    #[test]
+    fn test_render_expires_stale_series() {
+        let exporter = PrometheusExporter::new(Duration::ZERO);
+        exporter.record_batch(&make_batch("m", 1.0, vec![]));
+        std::thread::sleep(Duration::from_millis(5));
+
+        assert_eq!(exporter.render(false), "");
+    }
+}
@@ -0,0 +1,105 @@
+//! A lenient check of metric units against the Unified Code for Units of
+//! Measure (UCUM), which OpenTelemetry uses for `Metric.unit`. It knows the
+//! units commonly used for infrastructure metrics rather than all of UCUM, so
+//! it is only used for warnings.
+
+use std::sync::LazyLock;
+
+use regex::Regex;
+
+const PREFIXES: &[&str] = &[
+    "Y", "Z", "E", "P", "T", "G", "M", "k", "h", "da", "d", "c", "m", "u", "n", "p", "f", "a",
+    "Ki", "Mi", "Gi", "Ti", "Pi",
+];
+
+#[rustfmt::skip]
+const ATOMS: &[&str] = &[
+    // SI base and derived units
+    "m", "s", "g", "rad", "K", "cd", "mol", "sr", "Hz", "N", "Pa", "J", "W", "A", "V", "F",
+    "Ohm", "S", "Wb", "T", "H", "lm", "lx", "Bq", "Gy", "Sv", "Cel", "l", "L",
+    // Time
+    "min", "h", "d", "wk", "mo", "a",
+    // Information
+    "By", "bit", "Bd",
+    // Others
+    "%", "deg", "bar", "eV", "dB", "[ppm]", "[ppb]", "[degF]",
+];
+
+static ANNOTATION: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\{[^{}]*\}").unwrap());
+static EXPONENT: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"^(.*?)([+-]?\d+)?$").unwrap());
+
+/// Whether `unit` is a UCUM unit built from known atoms, such as `By`, `ms`,
+/// `%`, `1`, `By/s`, `kW.h` or `{packets}/s`
+pub fn is_valid_unit(unit: &str) -> bool {
+    if unit.is_empty() || unit.contains(char::is_whitespace) {
+        return false;
+    }
+
+    // Annotations such as {packets} are valid on their own and stand for 1
+    let unit = ANNOTATION.replace_all(unit, "");
+    let unit = unit.trim_start_matches('/').replace(['(', ')'], "");
+    if unit.is_empty() {
+        return true;
+    }
+
+    unit.split(['.', '/']).all(is_valid_term)
+}
+
+fn is_valid_term(term: &str) -> bool {
+    if term.is_empty() {
+        return false;
+    }
+    if term.chars().all(|c| c.is_ascii_digit()) {
+        return true;
+    }
+    let Some(captures) = EXPONENT.captures(term) else {
+        return false;
+    };
+    let atom = &captures[1];
+    ATOMS.contains(&atom)
+        || PREFIXES.iter().any(|prefix| {
+            atom.strip_prefix(prefix)
+                .is_some_and(|rest| ATOMS.contains(&rest))
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_units() {
+        for unit in [
+            "1",
+            "By",
+            "KiBy",
+            "MiBy",
+            "s",
+            "ms",
+            "us",
+            "%",
+            "Cel",
+            "W",
+            "kW.h",
+            "By/s",
+            "{packets}",
+            "{packets}/s",
+            "/s",
+            "m2",
+            "s-1",
+            "bit/s",
+            "Hz",
+        ] {
+            assert!(is_valid_unit(unit), "{} should be valid", unit);
+        }
+    }
+
+    #[test]
+    fn test_invalid_units() {
+        for unit in [
+            "", "bytes", "percent", "seconds", "MB", "kb/s", "By per s", "celsius", "..",
+        ] {
+            assert!(!is_valid_unit(unit), "{} should be invalid", unit);
+        }
+    }
+}